@@ -0,0 +1,35 @@
+//! Feeds arbitrary datagrams through packet parsing and command dispatch, run with
+//! `cargo fuzz run packet`. The seed corpus in `corpus/packet` holds one valid packet of each
+//! client command.
+
+#![no_main]
+
+use std::{
+    cell::RefCell,
+    net::{Ipv4Addr, SocketAddr},
+};
+
+use libfuzzer_sys::fuzz_target;
+use udpbd_vexfat::{Server, ServerConfig, VexFatConfig};
+
+thread_local! {
+    static SERVER: RefCell<Server> = RefCell::new(server());
+}
+
+/// Server for a scratch root holding a single file, writes land in that file.
+fn server() -> Server {
+    let root = std::env::temp_dir().join(format!("udpbd-vexfat-fuzz-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("file.bin"), [0xAA; 64 * 1024]).unwrap();
+
+    let config = ServerConfig::new(VexFatConfig::new(root))
+        .bind(Ipv4Addr::LOCALHOST)
+        .port(0);
+    Server::new(&config).unwrap()
+}
+
+fuzz_target!(|data: &[u8]| {
+    // replies go to the discard port, nothing needs to receive them
+    let client = SocketAddr::from((Ipv4Addr::LOCALHOST, 9));
+    SERVER.with(|server| server.borrow_mut().handle_packet(data, client, false));
+});
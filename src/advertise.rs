@@ -0,0 +1,64 @@
+use std::{net::Ipv4Addr, time::Duration};
+
+use anyhow::Context;
+use log::{info, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+/// mDNS service type the server is advertised as.
+pub const SERVICE_TYPE: &str = "_udpbd._udp.local.";
+
+/// How long to wait for the goodbye packets to go out when stopping.
+const UNREGISTER_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// mDNS advertisement of the server, withdrawn when dropped.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl Advertisement {
+    /// Announces the server listening on `bind`:`port` on the LAN.
+    /// When bound to all interfaces, every address of the host is announced.
+    pub fn start(bind: Ipv4Addr, port: u16) -> anyhow::Result<Self> {
+        let daemon = ServiceDaemon::new().context("Failed to start mDNS daemon")?;
+
+        let properties = [("version", env!("CARGO_PKG_VERSION"))];
+        let ip = if bind.is_unspecified() {
+            String::new()
+        } else {
+            bind.to_string()
+        };
+        let mut service = ServiceInfo::new(
+            SERVICE_TYPE,
+            "udpbd-vexfat",
+            "udpbd-vexfat.local.",
+            ip.as_str(),
+            port,
+            &properties[..],
+        )
+        .context("Failed to describe mDNS service")?;
+        if bind.is_unspecified() {
+            service = service.enable_addr_auto();
+        }
+
+        let fullname = service.get_fullname().to_string();
+        daemon
+            .register(service)
+            .context("Failed to register mDNS service")?;
+        info!("Advertising {fullname} on port {port} over mDNS");
+
+        Ok(Self { daemon, fullname })
+    }
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        match self.daemon.unregister(&self.fullname) {
+            Ok(status) => {
+                let _ = status.recv_timeout(UNREGISTER_TIMEOUT);
+            }
+            Err(err) => warn!("Failed to withdraw mDNS advertisement: {err}"),
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
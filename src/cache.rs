@@ -0,0 +1,93 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ops::Range,
+};
+
+/// Recently read sectors, the least recently used one is evicted once full.
+pub struct SectorCache {
+    capacity: usize,
+    /// Data and last use of every cached sector, by sector number.
+    sectors: HashMap<u64, (u64, Vec<u8>)>,
+    /// Cached sector numbers by their last use, oldest first.
+    by_use: BTreeMap<u64, u64>,
+    uses: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl SectorCache {
+    /// Cache holding up to `capacity` sectors.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            sectors: HashMap::new(),
+            by_use: BTreeMap::new(),
+            uses: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn contains(&self, sector: u64) -> bool {
+        self.sectors.contains_key(&sector)
+    }
+
+    /// Data of `sector` if cached, marking it as the most recently used.
+    pub fn get(&mut self, sector: u64) -> Option<&[u8]> {
+        let (used, data) = self.sectors.get_mut(&sector)?;
+        self.by_use.remove(used);
+        self.uses += 1;
+        *used = self.uses;
+        self.by_use.insert(self.uses, sector);
+
+        Some(data)
+    }
+
+    pub fn insert(&mut self, sector: u64, data: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.remove(sector..sector + 1);
+        while self.sectors.len() >= self.capacity {
+            let Some((_, oldest)) = self.by_use.pop_first() else {
+                break;
+            };
+            self.sectors.remove(&oldest);
+        }
+
+        self.uses += 1;
+        self.sectors.insert(sector, (self.uses, data.to_vec()));
+        self.by_use.insert(self.uses, sector);
+    }
+
+    /// Drops the cached sectors in `sectors`.
+    pub fn remove(&mut self, sectors: Range<u64>) {
+        for sector in sectors {
+            if let Some((used, _)) = self.sectors.remove(&sector) {
+                self.by_use.remove(&used);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.sectors.clear();
+        self.by_use.clear();
+    }
+}
+
+#[test]
+fn evicts_least_recently_used() {
+    let mut cache = SectorCache::new(2);
+    cache.insert(1, &[1]);
+    cache.insert(2, &[2]);
+    assert_eq!(cache.get(1), Some(&[1][..]));
+
+    cache.insert(3, &[3]);
+    assert!(cache.contains(1));
+    assert!(!cache.contains(2));
+    assert!(cache.contains(3));
+
+    cache.remove(0..2);
+    assert!(!cache.contains(1));
+}
@@ -0,0 +1,47 @@
+use std::{
+    collections::HashSet,
+    io::{self, BufRead},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+/// Clients whose read requests are dropped until resumed.
+pub type PausedClients = Arc<Mutex<HashSet<IpAddr>>>;
+
+const USAGE: &str = "pause <ip>, resume <ip>, paused";
+
+/// Reads operator commands from stdin on a background thread.
+pub fn spawn(paused: PausedClients) {
+    println!("Interactive mode, commands: {USAGE}");
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                break;
+            };
+
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next().map(str::parse::<IpAddr>)) {
+                (Some("pause"), Some(Ok(ip))) => {
+                    paused.lock().unwrap().insert(ip);
+                    println!("Paused serving {ip}");
+                }
+                (Some("resume"), Some(Ok(ip))) => {
+                    if paused.lock().unwrap().remove(&ip) {
+                        println!("Resumed serving {ip}");
+                    } else {
+                        println!("{ip} is not paused");
+                    }
+                }
+                (Some("paused"), None) => {
+                    for ip in paused.lock().unwrap().iter() {
+                        println!(" - {ip}");
+                    }
+                }
+                (None, _) => {}
+                _ => println!("Unknown command {line:?}, expected one of: {USAGE}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,469 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::vexfat::VexFat;
+
+/// FAT entry of a cluster that isn't part of a chain.
+const FAT_FREE: u32 = 0;
+/// FAT entry of a cluster marked as bad.
+const FAT_BAD: u32 = 0xFFFF_FFF7;
+/// FAT entry of the last cluster in a chain.
+const FAT_END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+/// Clusters are numbered from 2, the first two FAT entries are reserved.
+const FIRST_CLUSTER: u32 = 2;
+
+pub const ENTRY_SIZE: usize = 32;
+const ENTRY_END_OF_DIRECTORY: u8 = 0x00;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM_EXTENSION: u8 = 0xC0;
+const ENTRY_FILE_NAME: u8 = 0xC1;
+const ATTRIBUTE_DIRECTORY: u16 = 0x10;
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+/// UTC offset field of a timestamp recorded in UTC.
+const UTC_OFFSET_VALID: u8 = 0x80;
+
+/// Fields of the exFAT boot sector describing the volume layout.
+pub struct BootSector {
+    pub volume_length: u64,
+    pub fat_offset: u32,
+    pub fat_length: u32,
+    pub cluster_heap_offset: u32,
+    pub cluster_count: u32,
+    pub root_directory_cluster: u32,
+    pub sectors_per_cluster_shift: u8,
+}
+
+impl BootSector {
+    /// Reads the boot sector back from the start of the volume.
+    pub fn read(vexfat: &mut VexFat) -> io::Result<Self> {
+        let mut sector = vec![0; usize::from(vexfat.sector_size())];
+        vexfat.seek(0)?;
+        vexfat.read(&mut sector)?;
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(sector[offset..offset + 4].try_into().unwrap());
+
+        Ok(Self {
+            volume_length: u64::from_le_bytes(sector[72..80].try_into().unwrap()),
+            fat_offset: u32_at(80),
+            fat_length: u32_at(84),
+            cluster_heap_offset: u32_at(88),
+            cluster_count: u32_at(92),
+            root_directory_cluster: u32_at(96),
+            sectors_per_cluster_shift: sector[109],
+        })
+    }
+
+    /// First sector of the given cluster.
+    pub fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.cluster_heap_offset + ((cluster - FIRST_CLUSTER) << self.sectors_per_cluster_shift)
+    }
+}
+
+/// Reads the FAT, indexed by cluster number.
+pub fn read_fat(vexfat: &mut VexFat, boot: &BootSector) -> io::Result<Vec<u32>> {
+    let entries = boot.cluster_count as usize + FIRST_CLUSTER as usize;
+    let mut fat = vec![0; entries * 4];
+    vexfat.seek(boot.fat_offset)?;
+    vexfat.read(&mut fat)?;
+
+    Ok(fat
+        .chunks_exact(4)
+        .map(|entry| u32::from_le_bytes(entry.try_into().unwrap()))
+        .collect())
+}
+
+/// Clusters of an allocation, either contiguous or followed through the FAT.
+fn cluster_chain(fat: &[u32], first: u32, contiguous_clusters: Option<u32>) -> Vec<u32> {
+    if let Some(count) = contiguous_clusters {
+        return (first..first + count).collect();
+    }
+
+    let mut chain = vec![first];
+    while let Some(&next) = fat.get(chain[chain.len() - 1] as usize) {
+        if next < FIRST_CLUSTER || next as usize >= fat.len() || chain.len() >= fat.len() {
+            break;
+        }
+        chain.push(next);
+    }
+
+    chain
+}
+
+/// File or directory found in a directory of the volume.
+pub struct DirectoryEntry {
+    pub name: String,
+    pub is_directory: bool,
+    pub first_cluster: u32,
+    pub size: u64,
+    no_fat_chain: bool,
+    /// Volume offset of the File entry starting the entry set.
+    offset: u64,
+    /// Raw entry set, the File entry followed by its secondary entries.
+    set: Vec<u8>,
+}
+
+/// Reads the entries of the directory stored in the given clusters.
+fn read_directory(
+    vexfat: &mut VexFat,
+    boot: &BootSector,
+    clusters: &[u32],
+) -> io::Result<Vec<DirectoryEntry>> {
+    let cluster_size = usize::from(vexfat.sector_size()) << boot.sectors_per_cluster_shift;
+    let mut data = vec![0; cluster_size * clusters.len()];
+    for (chunk, &cluster) in data.chunks_exact_mut(cluster_size).zip(clusters) {
+        vexfat.seek(boot.cluster_sector(cluster))?;
+        vexfat.read(chunk)?;
+    }
+
+    let sector_size = u64::from(vexfat.sector_size());
+    let mut entries = Vec::new();
+    let mut records = data.chunks_exact(ENTRY_SIZE).enumerate();
+    while let Some((index, record)) = records.next() {
+        match record[0] {
+            ENTRY_END_OF_DIRECTORY => break,
+            ENTRY_FILE => {
+                let position = index * ENTRY_SIZE;
+                let offset = u64::from(boot.cluster_sector(clusters[position / cluster_size]))
+                    * sector_size
+                    + (position % cluster_size) as u64;
+                let attributes = u16::from_le_bytes([record[4], record[5]]);
+                let secondary: Vec<&[u8]> = records
+                    .by_ref()
+                    .take(usize::from(record[1]))
+                    .map(|(_, record)| record)
+                    .collect();
+                let Some(stream) = secondary
+                    .first()
+                    .filter(|stream| stream[0] == ENTRY_STREAM_EXTENSION)
+                else {
+                    continue;
+                };
+
+                let name: Vec<u16> = secondary[1..]
+                    .iter()
+                    .filter(|record| record[0] == ENTRY_FILE_NAME)
+                    .flat_map(|record| record[2..].chunks_exact(2))
+                    .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+                    .take(usize::from(stream[3]))
+                    .collect();
+
+                entries.push(DirectoryEntry {
+                    name: String::from_utf16_lossy(&name),
+                    is_directory: attributes & ATTRIBUTE_DIRECTORY != 0,
+                    first_cluster: u32::from_le_bytes(stream[20..24].try_into().unwrap()),
+                    size: u64::from_le_bytes(stream[24..32].try_into().unwrap()),
+                    no_fat_chain: stream[1] & FLAG_NO_FAT_CHAIN != 0,
+                    offset,
+                    set: [record]
+                        .into_iter()
+                        .chain(secondary)
+                        .flatten()
+                        .copied()
+                        .collect(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Reads the whole directory tree of the volume, paths are relative to the volume root and
+/// separated by `/`. Parents are listed before their contents.
+pub fn read_tree(vexfat: &mut VexFat) -> io::Result<Vec<(String, DirectoryEntry)>> {
+    let boot = BootSector::read(vexfat)?;
+    let fat = read_fat(vexfat, &boot)?;
+    let cluster_size = u64::from(vexfat.sector_size()) << boot.sectors_per_cluster_shift;
+
+    let mut tree = Vec::new();
+    let mut pending = vec![(
+        String::new(),
+        cluster_chain(&fat, boot.root_directory_cluster, None),
+    )];
+    while let Some((parent, clusters)) = pending.pop() {
+        for entry in read_directory(vexfat, &boot, &clusters)? {
+            let path = format!("{parent}{}", entry.name);
+
+            if entry.is_directory {
+                let contiguous_clusters = if entry.no_fat_chain {
+                    Some(entry.size.div_ceil(cluster_size) as u32)
+                } else {
+                    None
+                };
+                let clusters = cluster_chain(&fat, entry.first_cluster, contiguous_clusters);
+                pending.push((format!("{path}/"), clusters));
+            }
+
+            tree.push((path, entry));
+        }
+    }
+
+    Ok(tree)
+}
+
+/// Contiguous run of clusters holding part of a file's data.
+pub struct Extent {
+    /// Path of the file on the volume, as returned by [`read_tree`].
+    pub path: String,
+    pub volume_offset: u64,
+    pub file_offset: u64,
+    /// Length of the run, including the slack after the end of the file.
+    pub len: u64,
+    /// Bytes of the run that hold file data.
+    pub data_len: u64,
+}
+
+/// Where the data of every file lives on the volume, sorted by volume offset.
+pub fn file_extents(vexfat: &mut VexFat) -> io::Result<Vec<Extent>> {
+    let boot = BootSector::read(vexfat)?;
+    let fat = read_fat(vexfat, &boot)?;
+    let sector_size = u64::from(vexfat.sector_size());
+    let cluster_size = sector_size << boot.sectors_per_cluster_shift;
+
+    let mut extents = Vec::new();
+    for (path, entry) in read_tree(vexfat)? {
+        if entry.is_directory || entry.size == 0 {
+            continue;
+        }
+
+        let contiguous_clusters = if entry.no_fat_chain {
+            Some(entry.size.div_ceil(cluster_size) as u32)
+        } else {
+            None
+        };
+        let clusters = cluster_chain(&fat, entry.first_cluster, contiguous_clusters);
+
+        let mut file_offset = 0;
+        for run in clusters.chunk_by(|a, b| a + 1 == *b) {
+            let len = run.len() as u64 * cluster_size;
+            extents.push(Extent {
+                path: path.clone(),
+                volume_offset: u64::from(boot.cluster_sector(run[0])) * sector_size,
+                file_offset,
+                len,
+                data_len: len.min(entry.size.saturating_sub(file_offset)),
+            });
+            file_offset += len;
+        }
+    }
+    extents.sort_by_key(|extent| extent.volume_offset);
+
+    Ok(extents)
+}
+
+/// File entries rewritten to carry the timestamps in `times`, by path as returned by
+/// [`read_tree`], keyed by the volume offset of the entry they replace.
+pub fn timestamp_entries(
+    vexfat: &mut VexFat,
+    times: &HashMap<String, SystemTime>,
+) -> io::Result<BTreeMap<u64, [u8; ENTRY_SIZE]>> {
+    let mut entries = BTreeMap::new();
+    for (path, entry) in read_tree(vexfat)? {
+        let Some(&time) = times.get(&path) else {
+            continue;
+        };
+
+        let (timestamp, increment) = timestamp(time);
+        let mut set = entry.set;
+        // created, last modified and last accessed
+        for field in [8, 12, 16] {
+            set[field..field + 4].copy_from_slice(&timestamp.to_le_bytes());
+        }
+        set[20] = increment;
+        set[21] = increment;
+        set[22..25].fill(UTC_OFFSET_VALID);
+        let checksum = set_checksum(&set);
+        set[2..4].copy_from_slice(&checksum.to_le_bytes());
+
+        entries.insert(entry.offset, set[..ENTRY_SIZE].try_into().unwrap());
+    }
+
+    Ok(entries)
+}
+
+/// Checksum of an entry set, stored in its File entry, which is skipped over.
+fn set_checksum(set: &[u8]) -> u16 {
+    set.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != 2 && i != 3)
+        .fold(0u16, |checksum, (_, &byte)| {
+            checksum.rotate_right(1).wrapping_add(u16::from(byte))
+        })
+}
+
+/// exFAT timestamp of `time` in UTC, with two second resolution, and the 10 ms increments to
+/// add to it. Times outside 1980 to 2107 are clamped.
+fn timestamp(time: SystemTime) -> (u32, u8) {
+    let (seconds, millis) = match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_millis()),
+        Err(_) => (0, 0),
+    };
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let of_day = seconds.rem_euclid(86400) as u32;
+
+    if year < 1980 {
+        return (1 << 21 | 1 << 16, 0);
+    }
+    if year > 2107 {
+        return (
+            127 << 25 | 12 << 21 | 31 << 16 | 23 << 11 | 59 << 5 | 29,
+            199,
+        );
+    }
+
+    let timestamp = (year as u32 - 1980) << 25
+        | month << 21
+        | day << 16
+        | (of_day / 3600) << 11
+        | (of_day / 60 % 60) << 5
+        | (of_day % 60 / 2);
+    let increment = (of_day % 2 * 100 + millis / 10) as u8;
+
+    (timestamp, increment)
+}
+
+/// Year, month and day of the given day since 1970-01-01.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    // months counted from March, so the leap day comes last
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = (if month < 10 { month + 3 } else { month - 9 }) as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// Prints every allocation chain in the FAT as runs of consecutive clusters.
+pub fn dump_fat(vexfat: &mut VexFat) -> io::Result<()> {
+    let boot = BootSector::read(vexfat)?;
+    let fat = read_fat(vexfat, &boot)?;
+    let clusters = FIRST_CLUSTER..fat.len() as u32;
+
+    // chains start at allocated clusters that no other entry points to
+    let mut referenced = vec![false; fat.len()];
+    for &next in &fat[FIRST_CLUSTER as usize..] {
+        if clusters.contains(&next) {
+            referenced[next as usize] = true;
+        }
+    }
+
+    let free = clusters
+        .clone()
+        .filter(|&cluster| fat[cluster as usize] == FAT_FREE)
+        .count();
+    let bad = clusters
+        .clone()
+        .filter(|&cluster| fat[cluster as usize] == FAT_BAD)
+        .count();
+
+    println!(
+        "FAT at sector {} ({} sectors), cluster heap at sector {}",
+        boot.fat_offset, boot.fat_length, boot.cluster_heap_offset
+    );
+    println!(
+        " - {} clusters, {free} without a chain, {bad} bad, root directory at cluster {}",
+        boot.cluster_count, boot.root_directory_cluster
+    );
+
+    for head in clusters.clone() {
+        let entry = fat[head as usize];
+        if entry == FAT_FREE || entry == FAT_BAD || referenced[head as usize] {
+            continue;
+        }
+
+        let mut runs: Vec<(u32, u32)> = Vec::new();
+        let mut length = 0;
+        let mut cluster = head;
+        loop {
+            length += 1;
+            match runs.last_mut() {
+                Some((_, end)) if *end + 1 == cluster => *end = cluster,
+                _ => runs.push((cluster, cluster)),
+            }
+
+            let next = fat[cluster as usize];
+            if next == FAT_END_OF_CHAIN {
+                break;
+            }
+            if !clusters.contains(&next) || length > fat.len() {
+                println!(" ! chain {head} is broken at cluster {cluster}, next is {next:#x}");
+                break;
+            }
+            cluster = next;
+        }
+
+        let runs: Vec<String> = runs
+            .iter()
+            .map(|(start, end)| {
+                if start == end {
+                    format!("{start}")
+                } else {
+                    format!("{start}-{end}")
+                }
+            })
+            .collect();
+        println!(
+            " - chain {head} at sector {}: {length} clusters, {}",
+            boot.cluster_sector(head),
+            runs.join(", ")
+        );
+    }
+
+    println!(
+        "Contiguous files don't need a FAT chain, their clusters are listed as without a chain"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn file_timestamps() {
+    use std::time::Duration;
+
+    let root = crate::utils::test_dir("file-timestamps");
+    let file = std::fs::File::create(root.join("game.iso")).unwrap();
+    file.set_len(4096).unwrap();
+    // 2020-01-02 03:04:07.250 UTC
+    file.set_modified(UNIX_EPOCH + Duration::from_millis(1_577_934_247_250))
+        .unwrap();
+    drop(file);
+
+    let mut vexfat = crate::vexfat::test_vexfat(&root, |_| {});
+    let (_, entry) = read_tree(&mut vexfat)
+        .unwrap()
+        .into_iter()
+        .find(|(path, _)| path.ends_with("game.iso"))
+        .unwrap();
+
+    let modified = u32::from_le_bytes(entry.set[12..16].try_into().unwrap());
+    assert_eq!(
+        modified,
+        40 << 25 | 1 << 21 | 2 << 16 | 3 << 11 | 4 << 5 | 3
+    );
+    assert_eq!(entry.set[21], 125);
+    assert_eq!(entry.set[23], UTC_OFFSET_VALID);
+    assert_eq!(
+        set_checksum(&entry.set),
+        u16::from_le_bytes([entry.set[2], entry.set[3]])
+    );
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn calendar_dates() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    assert_eq!(civil_from_days(18_263), (2020, 1, 2));
+}
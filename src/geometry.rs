@@ -0,0 +1,208 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use arbitrary_int::u9;
+use log::{debug, info};
+
+use crate::protocol::{rdma_payload, DEFAULT_MTU};
+
+/// Largest block shift the protocol allows, 512 byte blocks.
+pub const MAX_BLOCK_SHIFT: u8 = 7;
+/// Block shift used throughout in safe mode, 32 byte blocks.
+pub const SAFE_MODE_BLOCK_SHIFT: u8 = 3;
+
+/// Block size changes within this window count towards thrashing detection.
+const BLOCK_SHIFT_THRASH_WINDOW: Duration = Duration::from_secs(5);
+/// Number of block size changes within the window considered as thrashing.
+const BLOCK_SHIFT_THRASH_CHANGES: usize = 10;
+
+/// How the data of a read is cut into blocks and RDMA packets. Picked per client from the
+/// size of its requests, so two consoles reading at once each get the block size that suits
+/// their own reads.
+#[derive(Clone, Debug)]
+pub struct BlockGeometry {
+    sector_size: u16,
+    pub block_shift: u8,
+    pub block_size: u16,
+    pub blocks_per_packet: u16,
+    /// Bytes of block data an RDMA packet can carry, follows from the MTU.
+    rdma_payload: u16,
+    pub blocks_per_socket: u16,
+    /// Upper bound for the block shift picked by `set_block_shift_sectors`.
+    pub max_block_shift: u8,
+    block_shift_changes: VecDeque<Instant>,
+    block_shift_thrashing: bool,
+    /// Last request size passed to `set_block_shift_sectors` and the block shift it picked.
+    last_block_shift_sectors: Option<(u16, u8)>,
+}
+
+impl BlockGeometry {
+    /// Geometry for a volume of `sector_size` byte sectors, with no block size picked yet.
+    pub fn new(sector_size: u16) -> Self {
+        Self {
+            sector_size,
+            block_shift: 0,
+            block_size: 0,
+            blocks_per_packet: 0,
+            rdma_payload: rdma_payload(DEFAULT_MTU) as u16,
+            blocks_per_socket: 0,
+            max_block_shift: MAX_BLOCK_SHIFT,
+            block_shift_changes: VecDeque::new(),
+            block_shift_thrashing: false,
+            last_block_shift_sectors: None,
+        }
+    }
+
+    pub fn set_block_shift(&mut self, shift: u8) {
+        if shift == self.block_shift {
+            return;
+        }
+
+        self.block_shift = shift;
+        self.block_size = 1 << (shift + 2);
+        self.blocks_per_packet = self.packet_blocks();
+        self.blocks_per_socket = self.sector_size / self.block_size;
+
+        let now = Instant::now();
+        self.block_shift_changes.push_back(now);
+        while let Some(changed_at) = self.block_shift_changes.front() {
+            if now.duration_since(*changed_at) <= BLOCK_SHIFT_THRASH_WINDOW {
+                break;
+            }
+            self.block_shift_changes.pop_front();
+        }
+
+        if self.block_shift_changes.len() < BLOCK_SHIFT_THRASH_CHANGES {
+            if self.block_shift_thrashing {
+                self.block_shift_thrashing = false;
+                debug!("Block size settled");
+            }
+            debug!("Block size changed to {}", self.block_size);
+        } else if !self.block_shift_thrashing {
+            // log once and stay quiet until the changes calm down
+            self.block_shift_thrashing = true;
+            info!(
+                "Block size changed {} times within {} seconds, client is alternating between request sizes; a fixed block size might perform better",
+                self.block_shift_changes.len(),
+                BLOCK_SHIFT_THRASH_WINDOW.as_secs()
+            );
+        }
+    }
+
+    /// Sizes RDMA packets for a network with the given MTU.
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.rdma_payload = rdma_payload(mtu) as u16;
+        self.last_block_shift_sectors = None;
+        if self.block_size > 0 {
+            self.blocks_per_packet = self.packet_blocks();
+        }
+    }
+
+    /// Number of blocks of the current size that fit in an RDMA packet.
+    fn packet_blocks(&self) -> u16 {
+        (self.rdma_payload / self.block_size).min(u9::MAX.value())
+    }
+
+    pub fn set_block_shift_sectors(&mut self, sectors: u16) {
+        if sectors == 0 {
+            // any block size is as good as another for no data, keep the current one
+            return;
+        }
+
+        // streaming reads repeat the same request size, skip working it out again
+        if let Some((last_sectors, shift)) = self.last_block_shift_sectors {
+            if last_sectors == sectors {
+                self.set_block_shift(shift.min(self.max_block_shift));
+                return;
+            }
+        }
+
+        // Optimize for:
+        // - the least number of network packets
+        // - the largest block size (faster on the PS2)
+        let size = u32::from(sectors) * u32::from(self.sector_size);
+        let payload = u32::from(self.rdma_payload);
+        // packets needed when each carries as many whole blocks of the size as fit
+        let packets = |block_size: u32| size.div_ceil(payload / block_size * block_size);
+        let packets_min = packets(32);
+        let packets_128 = packets(128);
+        let packets_256 = packets(256);
+        let packets_512 = packets(512);
+
+        let shift = {
+            if packets_512 == packets_min {
+                7 // 512 byte blocks
+            } else if packets_256 == packets_min {
+                6 // 256 byte blocks
+            } else if packets_128 == packets_min {
+                5 // 128 byte blocks
+            } else {
+                3 //  32 byte blocks
+            }
+        };
+        self.last_block_shift_sectors = Some((sectors, shift));
+
+        self.set_block_shift(shift.min(self.max_block_shift));
+    }
+}
+
+#[test]
+fn packet_size_follows_mtu() {
+    let mut geometry = BlockGeometry::new(512);
+
+    geometry.set_block_shift(5);
+    assert_eq!(geometry.blocks_per_packet, 11);
+
+    geometry.set_mtu(9000);
+    assert_eq!(geometry.blocks_per_packet, 70);
+    // with room for 17 of the largest blocks a 16 sector read fits in a single packet of them
+    geometry.set_block_shift_sectors(16);
+    assert_eq!(geometry.block_shift, 7);
+}
+
+#[test]
+fn block_shift_invariants() {
+    use std::cell::RefCell;
+
+    use proptest::{prop_assert, prop_assert_eq, test_runner::TestRunner};
+
+    use crate::protocol::{MAX_MTU, MIN_MTU};
+
+    let geometry = RefCell::new(BlockGeometry::new(512));
+
+    TestRunner::default()
+        .run(&(1..=u16::MAX, MIN_MTU..=MAX_MTU), |(sectors, mtu)| {
+            let mut geometry = geometry.borrow_mut();
+            geometry.set_mtu(mtu);
+            geometry.set_block_shift_sectors(sectors);
+
+            let size = u32::from(sectors) * u32::from(geometry.sector_size);
+            let payload = rdma_payload(mtu) as u32;
+            let packets = |block_size: u32| size.div_ceil(payload / block_size * block_size);
+            let block_size = u32::from(geometry.block_size);
+
+            // as few packets as the smallest blocks need, with no larger block doing as well
+            prop_assert_eq!(packets(block_size), packets(32));
+            for larger in [128, 256, 512]
+                .into_iter()
+                .filter(|&larger| larger > block_size)
+            {
+                prop_assert!(packets(larger) > packets(32));
+            }
+
+            // whole blocks per sector and per packet, so reads always make progress
+            prop_assert_eq!(u32::from(geometry.sector_size) % block_size, 0);
+            prop_assert!(geometry.blocks_per_packet > 0);
+            let blocks = u32::from(sectors) * u32::from(geometry.blocks_per_socket);
+            prop_assert!(blocks > 0);
+            prop_assert_eq!(
+                blocks.div_ceil(u32::from(geometry.blocks_per_packet)),
+                packets(32)
+            );
+
+            Ok(())
+        })
+        .unwrap();
+}
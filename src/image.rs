@@ -0,0 +1,84 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::info;
+
+use crate::vexfat::VexFat;
+
+/// Sectors read and written at a time, 1 MiB with 512 byte sectors.
+const CHUNK_SECTORS: u32 = 2048;
+
+/// How often progress is logged while exporting.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Writes every sector of the volume to `path`, giving an image that can be mounted or flashed.
+pub fn export(vexfat: &mut VexFat, path: &Path) -> io::Result<()> {
+    let sector_size = u64::from(vexfat.sector_size());
+    let sector_count = vexfat.sector_count();
+    let expected_len = u64::from(sector_count) * sector_size;
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    let mut buf = vec![0; (u64::from(CHUNK_SECTORS) * sector_size) as usize];
+
+    info!(
+        "Exporting {sector_count} sectors ({} MiB) to {}",
+        expected_len / 1024 / 1024,
+        path.display()
+    );
+
+    let started = Instant::now();
+    let mut last_progress = started;
+    vexfat.seek(0)?;
+    for start in (0..sector_count).step_by(CHUNK_SECTORS as usize) {
+        let sectors = (sector_count - start).min(CHUNK_SECTORS);
+        let chunk = &mut buf[..(u64::from(sectors) * sector_size) as usize];
+        vexfat.read(chunk)?;
+        file.write_all(chunk)?;
+
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            info!(
+                "Exported {}/{sector_count} sectors, {}s elapsed",
+                start + sectors,
+                started.elapsed().as_secs()
+            );
+        }
+    }
+    file.flush()?;
+
+    let len = file.get_ref().metadata()?.len();
+    if len != expected_len {
+        return Err(io::Error::other(format!(
+            "image is {len} bytes, expected {expected_len}"
+        )));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn export_matches_volume() {
+    let root = crate::utils::test_dir("export-image");
+    fs::write(root.join("file.bin"), [0xAA; 4096]).unwrap();
+
+    let out = crate::utils::test_dir("export-image-out");
+    let image = out.join("volume.img");
+    let mut vexfat = crate::vexfat::test_vexfat(&root, |_| {});
+    export(&mut vexfat, &image).unwrap();
+
+    let exported = fs::read(&image).unwrap();
+    let mut volume = vec![0; exported.len()];
+    vexfat.seek(0).unwrap();
+    vexfat.read(&mut volume).unwrap();
+    assert_eq!(
+        exported.len() as u64,
+        u64::from(vexfat.sector_count()) * u64::from(vexfat.sector_size())
+    );
+    assert!(exported == volume);
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
@@ -0,0 +1,201 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::utils::unsigned_rounded_up_div;
+
+const SECTOR_SIZE: usize = 2048;
+
+/// Sector the primary volume descriptor is in, after the system area.
+const PRIMARY_VOLUME_DESCRIPTOR_SECTOR: u32 = 16;
+
+/// Root directory sectors searched for SYSTEM.CNF, it is near the start on every disc.
+const MAX_ROOT_DIRECTORY_SECTORS: u32 = 16;
+
+/// Game on a PS2 disc image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Title {
+    /// Serial of the boot executable, e.g. `SCUS_971.24`.
+    pub serial: String,
+    /// Volume identifier of the disc, or the image file name when it is blank.
+    pub name: String,
+}
+
+/// Reads the serial from SYSTEM.CNF and the volume identifier of the PS2 image at `path`.
+/// Returns `None` for anything that isn't an ISO9660 image with a PS2 boot line.
+pub fn read_title(path: &Path) -> io::Result<Option<Title>> {
+    let mut file = File::open(path)?;
+
+    let Some(descriptor) = read_sector(&mut file, PRIMARY_VOLUME_DESCRIPTOR_SECTOR)? else {
+        return Ok(None);
+    };
+    if descriptor[0] != 1 || &descriptor[1..6] != b"CD001" {
+        return Ok(None);
+    }
+    let volume_id = String::from_utf8_lossy(&descriptor[40..72])
+        .trim()
+        .to_owned();
+    let root_extent = u32_at(&descriptor, 156 + 2);
+    let root_len = u32_at(&descriptor, 156 + 10);
+
+    let Some((extent, len)) = find_file(&mut file, root_extent, root_len, "SYSTEM.CNF")? else {
+        return Ok(None);
+    };
+    // SYSTEM.CNF is a handful of lines, the first sector holds the boot line
+    let Some(cnf) = read_sector(&mut file, extent)? else {
+        return Ok(None);
+    };
+    let cnf = String::from_utf8_lossy(&cnf[..(len as usize).min(SECTOR_SIZE)]);
+    let Some(serial) = boot_serial(&cnf) else {
+        return Ok(None);
+    };
+
+    let name = if volume_id.is_empty() {
+        path.file_stem()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+    } else {
+        volume_id
+    };
+
+    Ok(Some(Title { serial, name }))
+}
+
+/// Reads a whole sector, or returns `None` if the image ends before it.
+fn read_sector(file: &mut File, sector: u32) -> io::Result<Option<[u8; SECTOR_SIZE]>> {
+    let mut buf = [0; SECTOR_SIZE];
+    file.seek(SeekFrom::Start(u64::from(sector) * SECTOR_SIZE as u64))?;
+
+    match file.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf)),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    // both-endian fields, the little-endian half comes first
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Extent and length of the file called `name` in the directory at `extent`.
+fn find_file(file: &mut File, extent: u32, len: u32, name: &str) -> io::Result<Option<(u32, u32)>> {
+    let sectors = unsigned_rounded_up_div(len, SECTOR_SIZE as u32);
+
+    for sector in 0..sectors.min(MAX_ROOT_DIRECTORY_SECTORS) {
+        let Some(data) = read_sector(file, extent + sector)? else {
+            return Ok(None);
+        };
+
+        let mut pos = 0;
+        // records don't cross sectors, a zero length pads out the rest of one
+        while pos < SECTOR_SIZE && data[pos] != 0 {
+            let record_len = data[pos] as usize;
+            if record_len < 34 || pos + record_len > SECTOR_SIZE {
+                break;
+            }
+            let record = &data[pos..pos + record_len];
+            let name_len = record[32] as usize;
+            let is_dir = record[25] & 0x02 != 0;
+
+            if !is_dir && 33 + name_len <= record_len {
+                // names carry a `;1` version suffix
+                let record_name = record[33..33 + name_len].split(|&b| b == b';').next();
+                if record_name
+                    .is_some_and(|record_name| record_name.eq_ignore_ascii_case(name.as_bytes()))
+                {
+                    return Ok(Some((u32_at(record, 2), u32_at(record, 10))));
+                }
+            }
+
+            pos += record_len;
+        }
+    }
+
+    Ok(None)
+}
+
+/// Serial in the PS2 boot line of SYSTEM.CNF, e.g. `BOOT2 = cdrom0:\SLUS_203.12;1`.
+fn boot_serial(cnf: &str) -> Option<String> {
+    cnf.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("BOOT2") {
+            return None;
+        }
+
+        let executable = value.trim().rsplit(['\\', ':']).next()?;
+        let serial = executable.split(';').next()?.trim();
+        (!serial.is_empty()).then(|| serial.to_owned())
+    })
+}
+
+#[test]
+fn boot_lines() {
+    assert_eq!(
+        boot_serial("BOOT2 = cdrom0:\\SCUS_971.24;1\r\nVER = 1.00\r\nVMODE = NTSC\r\n").as_deref(),
+        Some("SCUS_971.24")
+    );
+    assert_eq!(
+        boot_serial("boot2=cdrom0:\\SLES_123.45;1").as_deref(),
+        Some("SLES_123.45")
+    );
+    // PS1 discs boot with BOOT instead
+    assert_eq!(boot_serial("BOOT = cdrom:\\SLUS_000.01;1"), None);
+}
+
+#[test]
+fn title_of_image() {
+    let dir = crate::utils::test_dir("iso-title");
+    let path = dir.join("game.iso");
+
+    let mut image = vec![0; SECTOR_SIZE * 20];
+    let record = |image: &mut [u8], pos: usize, extent: u32, len: u32, flags: u8, name: &[u8]| {
+        let record_len = 33 + name.len() + (name.len() + 1) % 2;
+        image[pos] = record_len as u8;
+        image[pos + 2..pos + 6].copy_from_slice(&extent.to_le_bytes());
+        image[pos + 10..pos + 14].copy_from_slice(&len.to_le_bytes());
+        image[pos + 25] = flags;
+        image[pos + 32] = name.len() as u8;
+        image[pos + 33..pos + 33 + name.len()].copy_from_slice(name);
+        record_len
+    };
+
+    let descriptor = SECTOR_SIZE * 16;
+    image[descriptor] = 1;
+    image[descriptor + 1..descriptor + 6].copy_from_slice(b"CD001");
+    image[descriptor + 40..descriptor + 72].copy_from_slice(&[b' '; 32]);
+    image[descriptor + 40..descriptor + 44].copy_from_slice(b"GT3 ");
+    record(
+        &mut image,
+        descriptor + 156,
+        18,
+        SECTOR_SIZE as u32,
+        0x02,
+        &[0],
+    );
+
+    let cnf = b"BOOT2 = cdrom0:\\SCUS_971.24;1\r\nVER = 1.00\r\n";
+    let mut pos = SECTOR_SIZE * 18;
+    pos += record(&mut image, pos, 18, SECTOR_SIZE as u32, 0x02, &[0]);
+    pos += record(&mut image, pos, 18, SECTOR_SIZE as u32, 0x02, &[1]);
+    pos += record(&mut image, pos, 19, 1, 0x02, b"MODULES");
+    record(&mut image, pos, 19, cnf.len() as u32, 0, b"SYSTEM.CNF;1");
+    image[SECTOR_SIZE * 19..SECTOR_SIZE * 19 + cnf.len()].copy_from_slice(cnf);
+    std::fs::write(&path, &image).unwrap();
+
+    assert_eq!(
+        read_title(&path).unwrap(),
+        Some(Title {
+            serial: String::from("SCUS_971.24"),
+            name: String::from("GT3"),
+        })
+    );
+
+    std::fs::write(&path, [0; SECTOR_SIZE]).unwrap();
+    assert_eq!(read_title(&path).unwrap(), None);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
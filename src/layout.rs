@@ -0,0 +1,115 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use crate::{
+    exfat::{self, BootSector},
+    vexfat::VexFat,
+};
+
+/// Writes the volume geometry and where every file and directory landed, one per line.
+pub fn export(vexfat: &mut VexFat, path: &Path) -> io::Result<()> {
+    let boot = BootSector::read(vexfat)?;
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+
+    writeln!(file, "sector_size {}", vexfat.sector_size())?;
+    writeln!(file, "sector_count {}", vexfat.sector_count())?;
+    writeln!(file, "volume_length {}", boot.volume_length)?;
+    writeln!(file, "fat_offset {}", boot.fat_offset)?;
+    writeln!(file, "fat_length {}", boot.fat_length)?;
+    writeln!(file, "cluster_heap_offset {}", boot.cluster_heap_offset)?;
+    writeln!(file, "cluster_count {}", boot.cluster_count)?;
+    writeln!(
+        file,
+        "root_directory_cluster {}",
+        boot.root_directory_cluster
+    )?;
+
+    for (path, entry) in exfat::read_tree(vexfat)? {
+        let kind = if entry.is_directory { "dir" } else { "file" };
+        writeln!(file, "{kind} {} {} {path}", entry.first_cluster, entry.size)?;
+    }
+
+    file.flush()
+}
+
+/// Geometry and entries of an exported layout, keyed by name and path.
+fn parse(path: &Path) -> io::Result<BTreeMap<String, String>> {
+    let mut layout = BTreeMap::new();
+
+    for line in fs::read_to_string(path)?.lines() {
+        let (key, value) = match line.split_once(' ') {
+            Some((kind @ ("dir" | "file"), rest)) => {
+                // kind cluster size path, paths may contain spaces
+                let mut fields = rest.splitn(3, ' ');
+                let (cluster, size) = (fields.next(), fields.next());
+                let entry_path = fields.next().unwrap_or_default();
+                let value = format!(
+                    "{kind} at cluster {}, {} bytes",
+                    cluster.unwrap_or_default(),
+                    size.unwrap_or_default()
+                );
+                (format!("/{entry_path}"), value)
+            }
+            Some((name, value)) => (name.to_owned(), value.to_owned()),
+            None => continue,
+        };
+        layout.insert(key, value);
+    }
+
+    Ok(layout)
+}
+
+/// Compares two exported layouts and prints the differences, returns whether they're identical.
+pub fn compare(a: &Path, b: &Path) -> io::Result<bool> {
+    let a_layout = parse(a)?;
+    let b_layout = parse(b)?;
+    let mut identical = true;
+
+    println!("Comparing {} and {}", a.display(), b.display());
+    for (key, a_value) in &a_layout {
+        match b_layout.get(key) {
+            Some(b_value) if b_value == a_value => {}
+            Some(b_value) => println!(" ~ {key}: {a_value} -> {b_value}"),
+            None => println!(" - {key}: {a_value}"),
+        }
+        identical &= b_layout.get(key) == Some(a_value);
+    }
+    for (key, b_value) in &b_layout {
+        if !a_layout.contains_key(key) {
+            println!(" + {key}: {b_value}");
+            identical = false;
+        }
+    }
+
+    if identical {
+        println!("Layouts are identical");
+    }
+
+    Ok(identical)
+}
+
+#[test]
+fn same_root_same_layout() {
+    let root = crate::utils::test_dir("same-layout");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("DVD").join("game.iso"), [1; 4096]).unwrap();
+    fs::write(root.join("readme.txt"), "hello").unwrap();
+
+    let out = crate::utils::test_dir("same-layout-out");
+    let layouts = [out.join("a.layout"), out.join("b.layout")];
+    for layout in &layouts {
+        let mut vexfat = crate::vexfat::test_vexfat(&root, |_| {});
+        export(&mut vexfat, layout).unwrap();
+    }
+
+    let contents = fs::read_to_string(&layouts[0]).unwrap();
+    assert!(contents.contains(" DVD/game.iso\n"));
+    assert!(compare(&layouts[0], &layouts[1]).unwrap());
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(out).unwrap();
+}
@@ -0,0 +1,27 @@
+//! Serves a directory to OPL on a PS2 as a virtual exFAT block device over UDPBD.
+//!
+//! [`VexFat`] maps the directory into a volume and [`Server`] answers UDPBD requests for it.
+
+#[cfg(feature = "mdns")]
+mod advertise;
+mod cache;
+mod console;
+pub mod exfat;
+pub mod geometry;
+pub mod image;
+pub mod iso;
+pub mod layout;
+pub mod logging;
+mod metrics;
+mod opl;
+mod overlay;
+pub mod probe;
+pub mod protocol;
+pub mod server;
+pub mod synthetic;
+mod ul;
+pub mod utils;
+pub mod vexfat;
+
+pub use server::{Server, ServerConfig};
+pub use vexfat::{VexFat, VexFatConfig};
@@ -0,0 +1,128 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use log::{
+    kv::{self, VisitSource},
+    Record,
+};
+
+/// How log lines are written.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// A JSON object per line, for log aggregators.
+    Json,
+}
+
+/// Writes `record` as a single line JSON object. Key-values attached to the record, such as
+/// `event`, `client` or `sector`, become fields of their own, events without one are `log`.
+pub fn format_json(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    let mut fields = Fields::default();
+    // a key-value that fails to render is left out rather than losing the whole line
+    let _ = record.key_values().visit(&mut fields);
+
+    let mut line = format!("{{\"time\":{time:.3},\"level\":");
+    push_string(&mut line, record.level().as_str());
+    if !fields.has_event {
+        line.push_str(",\"event\":\"log\"");
+    }
+    line.push_str(&fields.json);
+    line.push_str(",\"message\":");
+    push_string(&mut line, &record.args().to_string());
+    line.push('}');
+
+    writeln!(out, "{line}")
+}
+
+/// Key-values of a record, rendered as `,"key":value` pairs.
+#[derive(Default)]
+struct Fields {
+    json: String,
+    has_event: bool,
+}
+
+impl<'kvs> VisitSource<'kvs> for Fields {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.has_event |= key.as_str() == "event";
+
+        self.json.push(',');
+        push_string(&mut self.json, key.as_str());
+        self.json.push(':');
+        if let Some(number) = value.to_u64() {
+            let _ = write!(self.json, "{number}");
+        } else if let Some(number) = value.to_i64() {
+            let _ = write!(self.json, "{number}");
+        } else if let Some(flag) = value.to_bool() {
+            let _ = write!(self.json, "{flag}");
+        } else {
+            push_string(&mut self.json, &value.to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Appends `s` as a quoted JSON string.
+fn push_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[test]
+fn json_lines() {
+    use log::Level;
+
+    let format = |record: &Record| {
+        let mut out = Vec::new();
+        format_json(&mut out, record).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+
+    let fields = [
+        ("event", kv::Value::from("read")),
+        ("client", kv::Value::from("192.168.1.10:1234")),
+        ("sector", kv::Value::from(2048u32)),
+    ];
+    let line = format(
+        &Record::builder()
+            .level(Level::Info)
+            .args(format_args!("read of \"game\"\n"))
+            .key_values(&fields)
+            .build(),
+    );
+    assert!(line.starts_with("{\"time\":"));
+    assert!(line.ends_with(
+        ",\"level\":\"INFO\",\"event\":\"read\",\"client\":\"192.168.1.10:1234\",\"sector\":2048,\"message\":\"read of \\\"game\\\"\\n\"}\n"
+    ));
+
+    let line = format(
+        &Record::builder()
+            .level(Level::Warn)
+            .args(format_args!("plain"))
+            .build(),
+    );
+    assert!(line.ends_with(",\"level\":\"WARN\",\"event\":\"log\",\"message\":\"plain\"}\n"));
+}
@@ -1,26 +1,549 @@
-use std::path::PathBuf;
+use std::{
+    net::{Ipv4Addr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::atomic::Ordering,
+    time::Duration,
+};
 
-use clap::Parser;
-use server::Server;
+use anyhow::Context;
+use clap::{CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use udpbd_vexfat::{
+    exfat, image, layout,
+    logging::{self, LogFormat},
+    probe, protocol,
+    server::{
+        DiscoveryReply, Ipv4Net, Server, ServerConfig, DEFAULT_BEACON_INTERVAL,
+        DEFAULT_STORAGE_BACKOFF, DEFAULT_STORAGE_FAILURE_THRESHOLD,
+    },
+    synthetic, utils,
+    vexfat::{
+        parse_cluster_size, parse_sector_size, EmptyFiles, InvalidNames, SortBy, VexFat,
+        VexFatConfig, DEFAULT_READ_AHEAD_SECTORS,
+    },
+};
 
-mod protocol;
-mod server;
-mod vexfat;
-mod utils;
+/// Crate version along with the commit and date it was built from, for bug reports.
+const VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (",
+    env!("UDPBD_GIT_HASH"),
+    " ",
+    env!("UDPBD_BUILD_DATE"),
+    ")"
+);
+
+/// Full build details shown by `--version`, `-V` prints the short [`VERSION`].
+const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("UDPBD_GIT_HASH"),
+    "\nbuilt:  ",
+    env!("UDPBD_BUILD_DATE"),
+    "\ntarget: ",
+    env!("UDPBD_TARGET")
+);
 
 #[derive(Parser, Debug)]
-#[command(version, arg_required_else_help = true)]
+#[command(
+    version = VERSION,
+    long_version = LONG_VERSION,
+    arg_required_else_help = true,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
 pub struct Args {
-    /// Path to OPL root directory to map into vexFAT.
-    pub root: PathBuf,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[command(flatten)]
+    pub map: MapArgs,
+
+    /// UDP port to listen on. The PS2 only uses the default, change it for testing only.
+    #[arg(long, default_value_t = protocol::UDPBD_PORT)]
+    pub port: u16,
+
+    /// Local address to listen on, defaults to all interfaces.
+    /// Replies to discovery are broadcast from the interface with this address.
+    #[arg(long, value_name = "IP", default_value_t = Ipv4Addr::UNSPECIFIED)]
+    pub bind: Ipv4Addr,
+
+    /// Reread and compare every block sent to the client, logging any mismatch.
+    /// Also checks that every read sends exactly the requested number of bytes.
+    /// Doubles the read I/O, meant for diagnosing flaky host storage.
+    #[arg(long)]
+    pub verify_reads: bool,
+
+    /// Fetch N sectors past the end of each sequential read so the next one is served from
+    /// memory, 0 to disable.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_READ_AHEAD_SECTORS)]
+    pub read_ahead: u32,
+
+    /// Keep up to MIB mebibytes of recently read sectors in memory, for metadata and game headers
+    /// OPL reads over and over.
+    #[arg(long, value_name = "MIB", default_value_t = 0, value_parser = clap::value_parser!(u64).range(..=u64::MAX >> 20))]
+    pub cache_size: u64,
+
+    /// Pin the block size to 32 bytes for troubleshooting unreliable setups.
+    /// Small blocks are slower to process on the PS2, expect lower throughput.
+    #[arg(long)]
+    pub safe_mode: bool,
+
+    /// Consider library storage unavailable after N consecutive failed reads, e.g. when a
+    /// network mount drops, and stop logging every failure.
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_STORAGE_FAILURE_THRESHOLD, value_parser = clap::value_parser!(u32).range(1..))]
+    pub storage_failure_threshold: u32,
+
+    /// While library storage is unavailable, only try serving a read every MS milliseconds.
+    #[arg(long, value_name = "MS", default_value_t = DEFAULT_STORAGE_BACKOFF.as_millis() as u64)]
+    pub storage_backoff: u64,
+
+    /// Advertise the server on the LAN over mDNS as a _udpbd._udp service.
+    /// Needs a build with the mdns feature.
+    #[arg(long)]
+    pub advertise: bool,
+
+    /// Only answer clients in CIDR range (e.g. 192.168.1.0/24 or a single address), repeat to
+    /// allow several ranges. Every client is answered by default.
+    #[arg(long, value_name = "CIDR")]
+    pub allow: Vec<Ipv4Net>,
+
+    /// Periodically broadcast the server's presence so clients can discover it passively.
+    #[arg(long)]
+    pub beacon: bool,
+
+    /// Seconds between presence broadcasts.
+    #[arg(long, value_name = "SECS", default_value_t = DEFAULT_BEACON_INTERVAL.as_secs(), value_parser = clap::value_parser!(u64).range(1..), requires = "beacon")]
+    pub beacon_interval: u64,
+
+    /// Serve Prometheus metrics at http://ADDR/metrics, e.g. 127.0.0.1:9000.
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Accept commands on stdin to pause and resume serving individual clients.
+    #[arg(short, long)]
+    pub interactive: bool,
+
+    /// Log more, repeat for more detail (-v shows every request, -vv everything).
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Log less, repeat to only show errors.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub quiet: u8,
+
+    /// Format of the log lines, json writes an object per line with fields such as event, client
+    /// and sector for log aggregators.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// How to answer discovery requests.
+    #[arg(long, value_enum, default_value_t = DiscoveryReply::Auto)]
+    pub discovery_reply: DiscoveryReply,
+
+    /// Map root, print every mapped path and the volume size, and exit without serving.
+    /// Nothing is written, the directories OPL expects are listed instead of created.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// MTU of the network, larger values (jumbo frames) allow bigger RDMA packets.
+    /// The PS2 network adapter only supports the default.
+    #[arg(long, default_value_t = protocol::DEFAULT_MTU, value_parser = clap::value_parser!(u16).range(i64::from(protocol::MIN_MTU)..=i64::from(protocol::MAX_MTU)))]
+    pub mtu: u16,
+
+    /// Size of the socket receive buffer in bytes (e.g. 4M), raise it if write bursts drop
+    /// packets. The OS may clamp it.
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    pub rcvbuf: Option<u64>,
+
+    /// Size of the socket send buffer in bytes (e.g. 4M), smooths large read bursts.
+    /// The OS may clamp it.
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    pub sndbuf: Option<u64>,
+
+    /// Send the replies to reads from N background threads, so reading the next request
+    /// overlaps with sending the previous one. Reading from storage stays on the serving thread,
+    /// only sending moves. 0 sends them from the serving thread too.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub send_workers: usize,
+
+    /// DSCP value (0-63) to mark outgoing packets with, for networks with QoS.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..64))]
+    pub dscp: Option<u8>,
+
+    /// Keep writes from the PS2 in DIR instead of changing the mapped files.
+    /// Reads see the written data and it is reloaded on restart. Writes made while files were
+    /// laid out differently are set aside in DIR until the layout matches again.
+    #[arg(long, value_name = "DIR")]
+    pub overlay: Option<PathBuf>,
+
+    /// Refuse writes while free space on root is below SIZE (e.g. 512M).
+    /// A warning is printed once free space drops below twice that.
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    pub min_free_space: Option<u64>,
+}
+
+/// Options deciding what gets mapped and where, shared by serving and the subcommands that
+/// map a volume without serving it.
+#[derive(clap::Args, Debug)]
+pub struct MapArgs {
+    /// Path to OPL root directory to map into vexFAT. Pass several to merge them into one volume,
+    /// directories they have in common such as DVD are combined.
+    #[arg(required_unless_present_any = ["synthetic", "image"])]
+    pub root: Vec<PathBuf>,
 
     /// OPL prefix.
     #[arg(short, long)]
     pub prefix: Option<String>,
+
+    /// Order the entries of each directory are mapped in, which is how OPL lists them.
+    #[arg(long, value_enum, default_value_t = SortBy::Name)]
+    pub sort: SortBy,
+
+    /// How to handle zero-byte files.
+    #[arg(long, value_enum, default_value_t = EmptyFiles::Map)]
+    pub empty_files: EmptyFiles,
+
+    /// How to handle files and directories whose names exFAT can't store, such as names with
+    /// `:` or `?` or ending with a dot.
+    #[arg(long, value_enum, default_value_t = InvalidNames::Skip)]
+    pub invalid_names: InvalidNames,
+
+    /// Cluster size, a power of two from 512 to 32M. Smaller clusters waste less space after the
+    /// end of each file, larger ones keep the FAT of a big library small.
+    #[arg(long, value_name = "SIZE", default_value = "1M", value_parser = parse_cluster_size)]
+    pub cluster_size: u8,
+
+    /// Sector size, a power of two from 512 to 4K. OPL's UDPBD driver expects 512, others may
+    /// keep the PS2 from mounting the volume.
+    #[arg(long, value_name = "SIZE", default_value = "512", value_parser = parse_sector_size)]
+    pub sector_size: u8,
+
+    /// Don't create the directories OPL expects (APPS, CD, DVD, ...) in root, map it as is.
+    #[arg(long)]
+    pub no_create_dirs: bool,
+
+    /// Map the files and directories symlinks point to instead of skipping the links.
+    /// Broken links and links back to a parent directory are skipped with a warning.
+    #[arg(long)]
+    pub follow_symlinks: bool,
+
+    /// Skip files and directories matching GLOB (e.g. '*.txt' or 'Thumbs.db'), matched against
+    /// the path relative to root and the name alone. Repeat for more patterns.
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Only map files matching GLOB (e.g. '*.iso'), matched like --exclude. Repeat for more
+    /// patterns.
+    #[arg(long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Map macOS metadata such as AppleDouble `._*` files and `.DS_Store`, skipped by default.
+    #[arg(long)]
+    pub keep_sidecar_files: bool,
+
+    /// Read the serial and volume name of every mapped ISO image and log them, reads a few
+    /// sectors of each image while mapping.
+    #[arg(long)]
+    pub read_titles: bool,
+
+    /// Map the ISO images in DIR under CD or DVD by their size, without moving them.
+    #[arg(long, value_name = "DIR", conflicts_with = "image")]
+    pub staging: Option<PathBuf>,
+
+    /// List every game under CD and DVD with its size and a grand total once mapped.
+    #[arg(long)]
+    pub summary: bool,
+
+    /// Spread the files of any directory holding more than N of them over alphabetical
+    /// subdirectories (A-E, F-J, ...). Changes the layout OPL sees.
+    #[arg(long, value_name = "N")]
+    pub shard_over: Option<usize>,
+
+    /// Warn if the prefix in OPL's configuration doesn't match --prefix.
+    #[arg(long)]
+    pub check_opl_config: bool,
+
+    /// Map the files listed in FILE first so they end up at the start of the volume.
+    /// One file name or path relative to root per line, most played first.
+    #[arg(long, value_name = "FILE")]
+    pub map_order_by_popularity: Option<PathBuf>,
+
+    /// Map a generated volume of SIZE (e.g. 512M) filled with a known pattern instead of root.
+    /// Useful for testing connectivity and throughput.
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    pub synthetic: Option<u64>,
+
+    /// Use FILE, a prepared exFAT image or block device, as is instead of mapping root.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["root", "synthetic"])]
+    pub image: Option<PathBuf>,
+}
+
+impl MapArgs {
+    /// Checks what clap can't and generates the synthetic volume if one was asked for, so root
+    /// points at it.
+    fn prepare(&mut self) -> anyhow::Result<()> {
+        if self.cluster_size < self.sector_size {
+            Args::command()
+                .error(
+                    clap::error::ErrorKind::ArgumentConflict,
+                    "--cluster-size can't be smaller than --sector-size",
+                )
+                .exit();
+        }
+
+        if let Some(size) = self.synthetic {
+            let root = synthetic::generate(size).context("Failed to generate synthetic volume")?;
+            self.root = vec![root];
+        }
+        Ok(())
+    }
+
+    fn vexfat_config(&self) -> VexFatConfig {
+        let root = self
+            .image
+            .clone()
+            .or_else(|| self.root.first().cloned())
+            .expect("root directory is required");
+
+        VexFatConfig {
+            prefix: self.prefix.clone(),
+            empty_files: self.empty_files,
+            sort_by: self.sort,
+            invalid_names: self.invalid_names,
+            create_dirs: !self.no_create_dirs,
+            follow_symlinks: self.follow_symlinks,
+            check_opl_config: self.check_opl_config,
+            map_order_by_popularity: self.map_order_by_popularity.clone(),
+            keep_sidecar_files: self.keep_sidecar_files,
+            summary: self.summary,
+            staging: self.staging.clone(),
+            read_titles: self.read_titles,
+            extra_roots: self.root.iter().skip(1).cloned().collect(),
+            exclude: self.exclude.clone(),
+            include: self.include.clone(),
+            shard_over: self.shard_over,
+            image: self.image.is_some(),
+            sectors_per_cluster_shift: self.cluster_size - self.sector_size,
+            bytes_per_sector_shift: self.sector_size,
+            ..VexFatConfig::new(root)
+        }
+    }
+
+    /// Maps the volume without serving it.
+    fn open(&mut self) -> anyhow::Result<VexFat> {
+        self.prepare()?;
+        VexFat::new(&self.vexfat_config())
+    }
+}
+
+impl Args {
+    fn vexfat_config(&self) -> VexFatConfig {
+        VexFatConfig {
+            overlay: self.overlay.clone(),
+            read_ahead_sectors: self.read_ahead,
+            cache_size: self.cache_size * 1024 * 1024,
+            ..self.map.vexfat_config()
+        }
+    }
+
+    fn server_config(&self) -> ServerConfig {
+        ServerConfig {
+            bind: self.bind,
+            port: self.port,
+            dscp: self.dscp,
+            mtu: self.mtu,
+            recv_buffer_size: self.rcvbuf.map(|size| size as usize),
+            send_buffer_size: self.sndbuf.map(|size| size as usize),
+            send_workers: self.send_workers,
+            verify_reads: self.verify_reads,
+            safe_mode: self.safe_mode,
+            interactive: self.interactive,
+            discovery_reply: self.discovery_reply,
+            min_free_space: self.min_free_space,
+            storage_failure_threshold: self.storage_failure_threshold,
+            storage_backoff: Duration::from_millis(self.storage_backoff),
+            metrics_addr: self.metrics_addr,
+            advertise: self.advertise,
+            allow: self.allow.clone(),
+            beacon_interval: self
+                .beacon
+                .then(|| Duration::from_secs(self.beacon_interval)),
+            ..ServerConfig::new(self.vexfat_config())
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Print a completion script for the given shell to stdout.
+    Completions {
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+
+    /// Write every sector of the mapped volume to FILE, for mounting or flashing.
+    Export {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[command(flatten)]
+        map: MapArgs,
+    },
+
+    /// Write the volume geometry and where every file landed to FILE.
+    ExportLayout {
+        #[arg(value_name = "FILE")]
+        file: PathBuf,
+
+        #[command(flatten)]
+        map: MapArgs,
+    },
+
+    /// Compare two files written by export-layout, exits nonzero if they differ.
+    CompareLayouts {
+        #[arg(value_name = "A")]
+        a: PathBuf,
+
+        #[arg(value_name = "B")]
+        b: PathBuf,
+    },
+
+    /// Print the FAT allocation chains of the mapped volume.
+    DumpFat {
+        #[command(flatten)]
+        map: MapArgs,
+    },
+
+    /// Check whether a server at HOST answers discovery requests.
+    Probe {
+        #[arg(value_name = "HOST")]
+        host: String,
+
+        /// UDP port the server listens on.
+        #[arg(long, default_value_t = protocol::UDPBD_PORT)]
+        port: u16,
+
+        /// How many times to resend the request before giving up.
+        #[arg(long, default_value_t = 3)]
+        retries: u32,
+
+        /// How long to wait for each reply, in milliseconds.
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        timeout: u64,
+    },
 }
 
-fn main() {
-    let args = Args::parse();
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+
+    let level = match i16::from(args.verbose) - i16::from(args.quiet) {
+        ..=-2 => log::LevelFilter::Error,
+        -1 => log::LevelFilter::Warn,
+        0 => log::LevelFilter::Info,
+        1 => log::LevelFilter::Debug,
+        2.. => log::LevelFilter::Trace,
+    };
+    // RUST_LOG still takes precedence for finer grained filtering
+    let mut logger = env_logger::Builder::new();
+    logger
+        .filter_level(level)
+        .format_target(false)
+        .parse_default_env();
+    if args.log_format == LogFormat::Json {
+        logger.format(|buf, record| logging::format_json(buf, record));
+    }
+    logger.init();
+
+    match args.command.take() {
+        Some(Command::Completions { shell }) => {
+            let mut command = Args::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+        }
+        Some(Command::Export { file, mut map }) => {
+            let mut vexfat = map.open()?;
+            image::export(&mut vexfat, &file).context("Failed to export image")?;
+            println!("Image written to {}", file.display());
+        }
+        Some(Command::ExportLayout { file, mut map }) => {
+            let mut vexfat = map.open()?;
+            layout::export(&mut vexfat, &file).context("Failed to export layout")?;
+            println!("Layout written to {}", file.display());
+        }
+        Some(Command::CompareLayouts { a, b }) => {
+            let identical = layout::compare(&a, &b).context("Failed to compare layouts")?;
+            std::process::exit(if identical { 0 } else { 1 });
+        }
+        Some(Command::DumpFat { mut map }) => {
+            let mut vexfat = map.open()?;
+            exfat::dump_fat(&mut vexfat).context("Failed to read FAT")?;
+        }
+        Some(Command::Probe {
+            host,
+            port,
+            retries,
+            timeout,
+        }) => probe(&host, port, retries, Duration::from_millis(timeout))?,
+        None => serve(args)?,
+    }
+    Ok(())
+}
+
+/// Sends discovery requests to `host` and prints what the server answered, exits nonzero if it
+/// never did.
+fn probe(host: &str, port: u16, retries: u32, timeout: Duration) -> anyhow::Result<()> {
+    let server = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {host}"))?
+        .next()
+        .with_context(|| format!("{host} has no addresses"))?;
+    match probe::probe(server, retries, timeout).context("Failed to probe")? {
+        Some((reply, attempts)) => {
+            let (sector_size, sector_count) = (reply.sector_size, reply.sector_count);
+            println!(
+                "{server} answered after {attempts} attempt(s): {sector_count} sectors of {sector_size} bytes"
+            );
+            Ok(())
+        }
+        None => {
+            eprintln!(
+                "No reply from {server} after {} attempt(s) of {} ms",
+                retries + 1,
+                timeout.as_millis()
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn serve(mut args: Args) -> anyhow::Result<()> {
+    args.map.prepare()?;
+
+    if args.dry_run {
+        let mut config = args.vexfat_config();
+        for dir in config.dry_run() {
+            println!("would create {}", dir.display());
+        }
+        let mut vexfat = VexFat::new(&config)?;
+        let tree = exfat::read_tree(&mut vexfat).context("Failed to read the mapped volume")?;
+        for (path, _) in tree {
+            println!("ro:vexfat:{path}");
+        }
+        let size = u64::from(vexfat.sector_count()) * u64::from(vexfat.sector_size());
+        println!("size = {} MiB", size / 1024 / 1024);
+        return Ok(());
+    }
+
+    let mut server = Server::new(&args.server_config())?;
+
+    let shutdown = server.shutdown_handle();
+    ctrlc::set_handler(move || shutdown.store(true, Ordering::SeqCst))
+        .context("Failed to install Ctrl-C handler")?;
+
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, server.rescan_handle())
+        .context("Failed to install SIGHUP handler")?;
 
-    Server::new(&args).unwrap().run();
+    server.run()
 }
@@ -0,0 +1,146 @@
+use std::{
+    fmt::Write as _,
+    io::{self, BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use log::{debug, info};
+
+use crate::server::Stats;
+
+/// Latest figures published by the server, read by the metrics endpoint.
+pub type SharedMetrics = Arc<Mutex<Metrics>>;
+
+/// How long a scraper gets to send its request before it is dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    pub stats: Stats,
+    pub volume_size: u64,
+}
+
+impl Metrics {
+    /// Prometheus text exposition of the metrics.
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP udpbd_{name} {help}");
+            let _ = writeln!(out, "# TYPE udpbd_{name} {kind}");
+            let _ = writeln!(out, "udpbd_{name} {value}");
+        };
+
+        metric(
+            "bytes_read_total",
+            "counter",
+            "Bytes sent to clients in reads.",
+            self.stats.bytes_read,
+        );
+        metric(
+            "bytes_written_total",
+            "counter",
+            "Bytes received from clients in writes.",
+            self.stats.bytes_written,
+        );
+        metric(
+            "read_requests_total",
+            "counter",
+            "Read requests served.",
+            self.stats.read_requests,
+        );
+        metric(
+            "write_requests_total",
+            "counter",
+            "Write requests received.",
+            self.stats.write_requests,
+        );
+        metric(
+            "active_clients",
+            "gauge",
+            "Clients that read or wrote recently.",
+            self.stats.active_clients() as u64,
+        );
+        metric(
+            "volume_size_bytes",
+            "gauge",
+            "Size of the served volume.",
+            self.volume_size,
+        );
+
+        out
+    }
+}
+
+/// Serves the metrics at `http://addr/metrics` on a background thread.
+pub fn spawn(addr: SocketAddr, metrics: SharedMetrics) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(stream, &metrics));
+            if let Err(err) = result {
+                debug!("Failed to answer metrics request: {err}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn respond(mut stream: TcpStream, metrics: &SharedMetrics) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    // only the request line matters, headers and body are ignored
+    let mut request = String::new();
+    BufReader::new(&stream).read_line(&mut request)?;
+    let mut parts = request.split_whitespace();
+
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.lock().unwrap().render()),
+        _ => ("404 Not Found", String::from("Not found, try /metrics\n")),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[test]
+fn serves_prometheus_text() {
+    use std::{io::Read, net::Ipv4Addr};
+
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut stats = Stats::default();
+    stats.bytes_read = 4096;
+    let metrics = Arc::new(Mutex::new(Metrics {
+        stats,
+        volume_size: 1 << 20,
+    }));
+
+    let get = |path: &str| {
+        let mut client = TcpStream::connect(addr).unwrap();
+        write!(client, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        respond(stream, &metrics).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response = get("/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    assert!(response.contains("\nudpbd_bytes_read_total 4096\n"));
+    assert!(response.contains("\nudpbd_volume_size_bytes 1048576\n"));
+
+    assert!(get("/").starts_with("HTTP/1.1 404"));
+}
@@ -0,0 +1,44 @@
+use std::{fs, path::Path};
+
+use log::{info, warn};
+
+/// OPL configuration files that may be found in the OPL root.
+const CONFIG_FILES: [&str; 2] = ["CFG/conf_opl.cfg", "OPL/conf_opl.cfg"];
+
+/// Configuration key holding the path prefix OPL uses for BDM devices, which UDPBD is one of.
+const PREFIX_KEY: &str = "bdm_prefix";
+
+/// Warn if the prefix OPL is configured with doesn't match the prefix the volume is served with.
+pub fn check_config_prefix(root: &Path, prefix: Option<&str>) {
+    let expected = prefix.unwrap_or_default().trim_matches('/');
+    let mut checked = false;
+
+    for name in CONFIG_FILES {
+        let path = root.join(name);
+        let Ok(config) = fs::read_to_string(&path) else {
+            continue;
+        };
+        checked = true;
+
+        let configured = config
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .find(|(key, _)| key.trim() == PREFIX_KEY)
+            .map(|(_, value)| value.trim().trim_matches('/'))
+            .unwrap_or_default();
+
+        if configured != expected {
+            warn!(
+                "{} has {PREFIX_KEY}={configured:?} but the server prefix is {expected:?}, games will not show up in OPL",
+                path.display()
+            );
+        }
+    }
+
+    if !checked {
+        info!(
+            "No OPL configuration found in {}, skipping prefix check",
+            root.display()
+        );
+    }
+}
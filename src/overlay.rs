@@ -0,0 +1,217 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use log::{info, warn};
+
+const DATA_FILE: &str = "overlay.bin";
+const INDEX_FILE: &str = "overlay.idx";
+
+/// Sectors written by clients, kept in a separate directory instead of the mapped files.
+///
+/// The data file is a sparse image of the volume holding only written sectors, the index file
+/// starts with the fingerprint of the volume layout followed by the number of every sector
+/// present in the data file.
+pub struct Overlay {
+    data: File,
+    index: File,
+    sectors: BTreeSet<u64>,
+    sector_size: u64,
+}
+
+impl Overlay {
+    /// Opens the overlay in `dir` for the volume layout with fingerprint `layout`, reloading
+    /// previously written sectors. Sectors written for another layout no longer line up with the
+    /// files, such an overlay is set aside under the fingerprint of its layout and brought back
+    /// once the volume is laid out that way again.
+    pub fn open(dir: &Path, layout: u64, sector_size: u64) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let data_path = dir.join(DATA_FILE);
+        let index_path = dir.join(INDEX_FILE);
+
+        if let Some(current) = read_layout(&index_path)? {
+            if current != layout {
+                let (aside_data, aside_index) = set_aside_paths(dir, current);
+                // the index goes first, an overlay without one is empty
+                fs::rename(&index_path, &aside_index)?;
+                fs::rename(&data_path, &aside_data)?;
+                warn!(
+                    "Overlay in {} was written for a different layout of the volume, set aside as {}",
+                    dir.display(),
+                    aside_index.display()
+                );
+            }
+        }
+        if !index_path.exists() {
+            let (aside_data, aside_index) = set_aside_paths(dir, layout);
+            if aside_index.exists() {
+                fs::rename(&aside_data, &data_path)?;
+                fs::rename(&aside_index, &index_path)?;
+                info!(
+                    "Restored the overlay in {} set aside for this layout of the volume",
+                    dir.display()
+                );
+            }
+        }
+
+        let data = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(data_path)?;
+        let mut index = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(index_path)?;
+
+        let mut contents = Vec::new();
+        index.read_to_end(&mut contents)?;
+        let mut entries = contents
+            .chunks_exact(8)
+            .map(|entry| u64::from_le_bytes(entry.try_into().unwrap()));
+
+        // sectors of the data file missing from the index are never read, no need to truncate it
+        let sectors = match entries.next() {
+            Some(_) => entries.collect(),
+            None => {
+                index.write_all(&layout.to_le_bytes())?;
+                BTreeSet::new()
+            }
+        };
+
+        Ok(Self {
+            data,
+            index,
+            sectors,
+            sector_size,
+        })
+    }
+
+    /// Number of sectors the overlay holds.
+    pub fn sector_count(&self) -> usize {
+        self.sectors.len()
+    }
+
+    /// Replaces the parts of `buf`, read from `offset` of the volume, that were overwritten.
+    pub fn apply(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let end = offset + buf.len() as u64;
+        let sectors = offset / self.sector_size..end.div_ceil(self.sector_size);
+
+        for &sector in self.sectors.range(sectors) {
+            let sector_start = sector * self.sector_size;
+            let from = sector_start.max(offset);
+            let to = (sector_start + self.sector_size).min(end);
+
+            self.data.seek(SeekFrom::Start(from))?;
+            self.data
+                .read_exact(&mut buf[(from - offset) as usize..(to - offset) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Stores `buf` at `offset` of the volume. Sectors only partly covered by `buf` are first
+    /// filled in from `read_base`, which reads a whole sector of the underlying volume.
+    pub fn write(
+        &mut self,
+        offset: u64,
+        buf: &[u8],
+        mut read_base: impl FnMut(u64, &mut [u8]) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let end = offset + buf.len() as u64;
+        let sectors = offset / self.sector_size..end.div_ceil(self.sector_size);
+        let new_sectors: Vec<u64> = sectors
+            .filter(|sector| !self.sectors.contains(sector))
+            .collect();
+
+        let mut base = vec![0; self.sector_size as usize];
+        for &sector in &new_sectors {
+            let sector_start = sector * self.sector_size;
+            if sector_start < offset || sector_start + self.sector_size > end {
+                read_base(sector_start, &mut base)?;
+                self.data.seek(SeekFrom::Start(sector_start))?;
+                self.data.write_all(&base)?;
+            }
+        }
+
+        self.data.seek(SeekFrom::Start(offset))?;
+        self.data.write_all(buf)?;
+
+        // only record sectors once their data is in place
+        for sector in new_sectors {
+            self.index.write_all(&sector.to_le_bytes())?;
+            self.sectors.insert(sector);
+        }
+
+        Ok(())
+    }
+}
+
+/// Layout fingerprint at the start of the index in `path`, if there is one.
+fn read_layout(path: &Path) -> io::Result<Option<u64>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    let mut layout = [0; 8];
+    match file.read_exact(&mut layout) {
+        Ok(()) => Ok(Some(u64::from_le_bytes(layout))),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Where the data and index files of an overlay for another layout are kept.
+fn set_aside_paths(dir: &Path, layout: u64) -> (PathBuf, PathBuf) {
+    (
+        dir.join(format!("overlay-{layout:016x}.bin")),
+        dir.join(format!("overlay-{layout:016x}.idx")),
+    )
+}
+
+#[test]
+fn overlay_persists_and_merges() {
+    let dir = crate::utils::test_dir("overlay");
+    let base = |_: u64, sector: &mut [u8]| {
+        sector.fill(0x11);
+        Ok(())
+    };
+
+    let mut overlay = Overlay::open(&dir, 4096, 512).unwrap();
+    // covers the end of sector 0 and the start of sector 1
+    overlay.write(500, &[0x22; 24], base).unwrap();
+    drop(overlay);
+
+    let mut overlay = Overlay::open(&dir, 4096, 512).unwrap();
+    assert_eq!(overlay.sector_count(), 2);
+
+    let mut buf = [0; 1536];
+    overlay.apply(0, &mut buf).unwrap();
+    assert!(buf[..500].iter().all(|&byte| byte == 0x11));
+    assert!(buf[500..524].iter().all(|&byte| byte == 0x22));
+    assert!(buf[524..1024].iter().all(|&byte| byte == 0x11));
+    // sector 2 was never written and keeps what was read
+    assert!(buf[1024..].iter().all(|&byte| byte == 0));
+
+    // a different layout sets the overlay aside instead of discarding it
+    let mut overlay = Overlay::open(&dir, 8192, 512).unwrap();
+    assert_eq!(overlay.sector_count(), 0);
+    overlay.write(0, &[0x33; 512], base).unwrap();
+    drop(overlay);
+
+    // and the first layout gets its sectors back, the other one's are set aside in turn
+    let mut overlay = Overlay::open(&dir, 4096, 512).unwrap();
+    assert_eq!(overlay.sector_count(), 2);
+    overlay.apply(0, &mut buf).unwrap();
+    assert!(buf[500..524].iter().all(|&byte| byte == 0x22));
+    assert!(set_aside_paths(&dir, 8192).1.exists());
+
+    fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,74 @@
+use std::{
+    io,
+    mem::size_of,
+    net::{SocketAddr, UdpSocket},
+    time::Duration,
+};
+
+use crate::protocol::{Command, Header, InfoReply, InfoRequest};
+
+/// Send an InfoRequest to `server`, resending up to `retries` times and waiting `timeout` for
+/// each reply. Returns the reply and the attempt it arrived on, or `None` if none did.
+pub fn probe(
+    server: SocketAddr,
+    retries: u32,
+    timeout: Duration,
+) -> io::Result<Option<(InfoReply, u32)>> {
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_read_timeout(Some(timeout))?;
+
+    let req = InfoRequest {
+        header: Header::new_with_raw_value(0).with_command(Command::Info),
+    };
+
+    let mut buf = [0; size_of::<InfoReply>()];
+    for attempt in 1..=retries + 1 {
+        socket.send_to(bytemuck::bytes_of(&req), server)?;
+
+        match socket.recv_from(&mut buf) {
+            Ok((len, _)) if len == buf.len() => {
+                let reply: InfoReply = bytemuck::pod_read_unaligned(&buf);
+                if let Some(Command::InfoReply) = reply.header.command() {
+                    return Ok(Some((reply, attempt)));
+                }
+            }
+            Ok(_) => {}
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(None)
+}
+
+#[test]
+fn retries_until_reply() {
+    let responder = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+    let server = responder.local_addr().unwrap();
+
+    std::thread::spawn(move || {
+        let mut buf = [0; size_of::<InfoRequest>()];
+        // drop the first request, answer the second
+        responder.recv_from(&mut buf).unwrap();
+        let (_, addr) = responder.recv_from(&mut buf).unwrap();
+        let reply = InfoReply {
+            header: Header::new_with_raw_value(0)
+                .with_command(Command::InfoReply)
+                .with_command_pkt(1),
+            sector_size: 512,
+            sector_count: 1024,
+        };
+        responder.send_to(bytemuck::bytes_of(&reply), addr).unwrap();
+    });
+
+    let (reply, attempts) = probe(server, 3, Duration::from_millis(200))
+        .unwrap()
+        .expect("no reply");
+    assert_eq!(attempts, 2);
+    assert_eq!({ reply.sector_count }, 1024);
+}
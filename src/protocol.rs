@@ -79,6 +79,13 @@ pub struct WriteReply {
     pub result: i32,
 }
 
+/// WriteReply result of a successful write.
+pub const WRITE_RESULT_OK: i32 = 0;
+/// WriteReply result of a failed write, -EIO.
+pub const WRITE_RESULT_ERROR: i32 = -5;
+/// WriteReply result sent back for a command the server doesn't know, -ENOSYS.
+pub const RESULT_UNSUPPORTED: i32 = -38;
+
 #[bitfield(u32)]
 #[repr(packed)]
 #[derive(Zeroable, Pod)]
@@ -102,17 +109,40 @@ impl BlockType {
     }
 }
 
-/// Maximum payload for an RDMA packet depends on the used block size:
+/// MTU of standard Ethernet, what the PS2 network adapter uses.
+pub const DEFAULT_MTU: u16 = 1500;
+/// Largest MTU packets can be sized for, jumbo frames.
+pub const MAX_MTU: u16 = 9000;
+/// Smallest MTU that still fits a 512 byte block in an RDMA packet.
+pub const MIN_MTU: u16 = 576;
+
+/// Size of the IPv4 and UDP headers in front of the payload.
+const IP_UDP_HEADERS: usize = 20 + 8;
+
+/// Largest UDP payload that fits in a packet of `mtu` bytes.
+pub const fn udp_payload(mtu: u16) -> usize {
+    mtu as usize - IP_UDP_HEADERS
+}
+
+/// Largest block data an RDMA packet of `mtu` bytes carries. How much of it is used depends on
+/// the block size, with the default MTU:
 /// -   4 * 366 = 1464 bytes
 /// -   8 * 183 = 1464 bytes
 /// -  16 *  91 = 1456 bytes
 /// -  32 *  45 = 1440 bytes
 /// -  64 *  22 = 1408 bytes
-/// - 128 *  11 = 1408 bytes <- default
+/// - 128 *  11 = 1408 bytes <- default, see [`DEFAULT_BLOCK_SHIFT`]
 /// - 256 *   5 = 1280 bytes
 /// - 512 *   2 = 1024 bytes
-pub const UDP_MAX_PAYLOAD: usize = 1472;
-pub const RDMA_MAX_PAYLOAD: usize = UDP_MAX_PAYLOAD - size_of::<Header>() - size_of::<BlockType>();
+pub const fn rdma_payload(mtu: u16) -> usize {
+    udp_payload(mtu) - size_of::<Header>() - size_of::<BlockType>()
+}
+
+/// Receive buffers and RDMA packets are sized for the largest MTU.
+pub const UDP_MAX_PAYLOAD: usize = udp_payload(MAX_MTU);
+/// Block shift used until a read request picks one, 128 byte blocks.
+pub const DEFAULT_BLOCK_SHIFT: u8 = 5;
+pub const RDMA_MAX_PAYLOAD: usize = rdma_payload(MAX_MTU);
 
 /// Remote DMA (RDMA) packet
 /// Used for transfering large blocks of data.
@@ -144,3 +174,20 @@ fn max_blocks_size() {
         187392
     )
 }
+
+#[test]
+fn default_mtu_payload() {
+    assert_eq!(udp_payload(DEFAULT_MTU), 1472);
+    assert_eq!(rdma_payload(DEFAULT_MTU), 1466);
+}
+
+#[test]
+fn default_block_size() {
+    assert_eq!(
+        BlockType::new_with_raw_value(0)
+            .with_block_shift(u4::new(DEFAULT_BLOCK_SHIFT))
+            .with_block_count(u9::new(1))
+            .blocks_size(),
+        128
+    )
+}
@@ -1,90 +1,1495 @@
 use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
+    hash::{Hash, Hasher},
+    io,
     mem::size_of,
     net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket},
+    ops::Range,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use arbitrary_int::{u4, u9};
+use arbitrary_int::{u3, u4, u5, u9};
+use bytemuck::Zeroable;
+use clap::ValueEnum;
+use log::{debug, error, info, warn};
+
+#[cfg(feature = "mdns")]
+use crate::advertise::Advertisement;
+use crate::{
+    console::{self, PausedClients},
+    geometry::{BlockGeometry, SAFE_MODE_BLOCK_SHIFT},
+    metrics::{self, Metrics, SharedMetrics},
+    protocol::{
+        BlockType, Command, Header, InfoReply, InfoRequest, Rdma, ReadWriteRequest, WriteReply,
+        DEFAULT_BLOCK_SHIFT, DEFAULT_MTU, RESULT_UNSUPPORTED, UDPBD_PORT, UDP_MAX_PAYLOAD,
+        WRITE_RESULT_ERROR, WRITE_RESULT_OK,
+    },
+    vexfat::{VexFat, VexFatConfig, Volume},
+};
+
+/// Where to send the reply to an InfoRequest.
+///
+/// Unicast replies are what the server always did. If a client doesn't detect the server,
+/// try answering with a broadcast instead.
+///
+/// What the clients need:
+/// - OPL 1.2.0 beta builds with UDPBD and Neutrino broadcast the request and take a reply from
+///   any address, `auto` and `unicast` both work.
+/// - Clients given the server's IP send the request straight to it and expect the reply the
+///   same way, `auto` answers them with a unicast.
+/// - Older or modified OPL builds that only pick up replies sent to the broadcast address, as
+///   happens when the console has no IP yet, need `broadcast`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DiscoveryReply {
+    /// Broadcast the reply if the request was a broadcast, unicast otherwise.
+    /// Only Linux can tell broadcast requests apart, elsewhere this is the same as unicast.
+    Auto,
+    /// Always reply directly to the client.
+    Unicast,
+    /// Always broadcast the reply on the client's port.
+    Broadcast,
+}
+
+/// IPv4 address range in CIDR notation, e.g. `192.168.1.0/24`. A bare address is a /32.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Ipv4Net {
+    addr: Ipv4Addr,
+    prefix_len: u8,
+}
+
+impl Ipv4Net {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let IpAddr::V4(ip) = ip else {
+            return false;
+        };
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(self.prefix_len))
+            .unwrap_or(0);
+
+        u32::from(ip) & mask == u32::from(self.addr) & mask
+    }
+}
+
+impl FromStr for Ipv4Net {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').unwrap_or((s, "32"));
+        let addr = addr
+            .parse()
+            .map_err(|err| format!("invalid address {addr:?}: {err}"))?;
+        let prefix_len = prefix_len
+            .parse()
+            .ok()
+            .filter(|&len| len <= 32)
+            .ok_or_else(|| format!("invalid prefix length {prefix_len:?}, expected 0-32"))?;
+
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// How long the broadcast addresses of the interfaces are trusted before being looked up again,
+/// they change with DHCP leases and interfaces coming and going.
+#[cfg(target_os = "linux")]
+const INTERFACES_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Broadcast addresses of the network interfaces by interface index, to tell broadcasts to a
+/// subnet apart from packets sent to the server's own address.
+#[cfg(target_os = "linux")]
+#[derive(Default)]
+struct InterfaceBroadcasts {
+    addrs: HashMap<u32, Vec<Ipv4Addr>>,
+    read_at: Option<Instant>,
+}
+
+#[cfg(target_os = "linux")]
+impl InterfaceBroadcasts {
+    /// Whether `ip` is a broadcast address of interface `ifindex` the packet arrived on.
+    fn is_broadcast(&mut self, ip: Ipv4Addr, ifindex: u32) -> bool {
+        if ip.is_broadcast() {
+            return true;
+        }
+
+        let age = self.read_at.map(|at| at.elapsed());
+        let stale = age.map_or(true, |age| age >= INTERFACES_REFRESH_INTERVAL);
+        // an interface that just came up, look again but not for every packet
+        let unknown = !self.addrs.contains_key(&ifindex)
+            && age.map_or(true, |age| age >= Duration::from_secs(1));
+        if stale || unknown {
+            self.refresh();
+        }
+
+        self.addrs
+            .get(&ifindex)
+            .is_some_and(|addrs| addrs.contains(&ip))
+    }
+
+    fn refresh(&mut self) {
+        use nix::{ifaddrs::getifaddrs, net::if_::if_nametoindex};
+        use std::net::SocketAddrV4;
+
+        self.read_at = Some(Instant::now());
+        self.addrs.clear();
+        let interfaces = match getifaddrs() {
+            Ok(interfaces) => interfaces,
+            Err(err) => {
+                warn!("Failed to list network interfaces, only 255.255.255.255 counts as broadcast: {err}");
+                return;
+            }
+        };
+        for interface in interfaces {
+            let Some(broadcast) = interface
+                .broadcast
+                .as_ref()
+                .and_then(|addr| addr.as_sockaddr_in())
+            else {
+                continue;
+            };
+            let Ok(index) = if_nametoindex(interface.interface_name.as_str()) else {
+                continue;
+            };
+            self.addrs
+                .entry(index)
+                .or_default()
+                .push(*SocketAddrV4::from(*broadcast).ip());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+thread_local! {
+    static INTERFACE_BROADCASTS: std::cell::RefCell<InterfaceBroadcasts> =
+        std::cell::RefCell::new(InterfaceBroadcasts::default());
+}
+
+/// Binds a UDP socket to `addr`, `reuse_address` lets the discovery socket share its port.
+fn bind_udp(addr: SocketAddr, reuse_address: bool) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if reuse_address {
+        socket.set_reuse_address(true)?;
+    }
+    socket.bind(&addr.into())?;
+
+    Ok(socket.into())
+}
+
+/// Opens a socket on the wildcard address for the broadcast discovery requests a socket bound
+/// to `bind` misses. It is tied to the interface that has `bind` where the OS allows it.
+#[cfg(target_os = "linux")]
+fn open_discovery_socket(bind: Ipv4Addr, port: u16) -> anyhow::Result<UdpSocket> {
+    use nix::sys::socket::{
+        setsockopt,
+        sockopt::{BindToDevice, Ipv4PacketInfo},
+    };
+    use std::{ffi::OsString, net::SocketAddrV4, os::fd::AsRawFd};
+
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+    let socket =
+        bind_udp(addr, true).with_context(|| format!("Failed to bind UDP socket to {addr}"))?;
+    setsockopt(socket.as_raw_fd(), Ipv4PacketInfo, &true)
+        .context("Failed to enable packet info on discovery socket")?;
+
+    let interface = nix::ifaddrs::getifaddrs()
+        .context("Failed to list network interfaces")?
+        .find(|interface| {
+            interface
+                .address
+                .as_ref()
+                .and_then(|addr| addr.as_sockaddr_in())
+                .is_some_and(|addr| *SocketAddrV4::from(*addr).ip() == bind)
+        })
+        .map(|interface| interface.interface_name);
+    match interface {
+        Some(name) => {
+            match setsockopt(socket.as_raw_fd(), BindToDevice, &OsString::from(&name)) {
+                Ok(()) => info!("Receiving broadcast discovery requests on {name}"),
+                Err(err) => warn!(
+                    "Failed to limit discovery to {name}, answering broadcasts from every interface: {err}"
+                ),
+            }
+        }
+        None => warn!("No interface has address {bind}, answering broadcasts from every interface"),
+    }
+
+    Ok(socket)
+}
+
+/// Waits up to [`SHUTDOWN_POLL_INTERVAL`] for a packet on either socket, returns whether the
+/// discovery socket has one or `None` on timeout. Discovery goes first, a busy `socket` would
+/// otherwise keep new clients from finding the server.
+#[cfg(target_os = "linux")]
+fn poll_sockets(socket: &UdpSocket, discovery_socket: &UdpSocket) -> io::Result<Option<bool>> {
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::os::fd::AsRawFd;
+
+    let events = PollFlags::POLLIN;
+    let mut fds = [
+        PollFd::new(discovery_socket.as_raw_fd(), events),
+        PollFd::new(socket.as_raw_fd(), events),
+    ];
+    if poll(&mut fds, SHUTDOWN_POLL_INTERVAL.as_millis() as i32)? == 0 {
+        return Ok(None);
+    }
+    let ready = |fd: &PollFd| {
+        fd.revents()
+            .is_some_and(|revents| revents.intersects(PollFlags::POLLIN | PollFlags::POLLERR))
+    };
+
+    Ok(if ready(&fds[0]) {
+        Some(true)
+    } else if ready(&fds[1]) {
+        Some(false)
+    } else {
+        None
+    })
+}
+
+/// There is never a discovery socket on this platform.
+#[cfg(not(target_os = "linux"))]
+fn poll_sockets(_socket: &UdpSocket, _discovery_socket: &UdpSocket) -> io::Result<Option<bool>> {
+    Ok(Some(false))
+}
+
+fn is_info_request(buf: &[u8]) -> bool {
+    buf.get(..size_of::<Header>())
+        .map(bytemuck::pod_read_unaligned::<Header>)
+        .is_some_and(|header| matches!(header.command(), Ok(Command::Info)))
+}
+
+/// Receive a packet, also reporting whether it was sent to a broadcast address.
+#[cfg(target_os = "linux")]
+fn recv_packet(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, bool)> {
+    use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, SockaddrIn};
+    use std::{io::IoSliceMut, net::SocketAddrV4, os::fd::AsRawFd};
+
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = nix::cmsg_space!(nix::libc::in_pktinfo);
+    let msg = recvmsg::<SockaddrIn>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::empty(),
+    )?;
+
+    let broadcast = msg.cmsgs().any(|cmsg| match cmsg {
+        ControlMessageOwned::Ipv4PacketInfo(info) => {
+            let ip = Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr));
+            INTERFACE_BROADCASTS.with(|interfaces| {
+                interfaces
+                    .borrow_mut()
+                    .is_broadcast(ip, info.ipi_ifindex as u32)
+            })
+        }
+        _ => false,
+    });
+    let addr = msg
+        .address
+        .map(|addr| SocketAddr::V4(SocketAddrV4::from(addr)))
+        .ok_or_else(|| io::Error::other("packet without source address"))?;
+
+    Ok((msg.bytes, addr, broadcast))
+}
+
+/// Receive a packet, broadcasts can't be told apart on this platform.
+#[cfg(not(target_os = "linux"))]
+fn recv_packet(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, bool)> {
+    let (len, addr) = socket.recv_from(buf)?;
+
+    Ok((len, addr, false))
+}
+
+/// RDMA packets of a read sent together, bounds the buffered replies and the delay before the
+/// first one goes out.
+const SEND_BATCH: usize = 32;
+
+/// RDMA packets of a read waiting to be sent. The packets are built in place and the batch is
+/// reused for later reads, so sending doesn't allocate per packet.
+struct PacketBatch {
+    packets: Vec<Rdma>,
+    /// Bytes of each packet to send, the headers and the data of its blocks.
+    lens: Vec<usize>,
+}
+
+impl PacketBatch {
+    fn new() -> Self {
+        Self {
+            packets: vec![Rdma::zeroed(); SEND_BATCH],
+            lens: Vec::with_capacity(SEND_BATCH),
+        }
+    }
+
+    /// Packet to fill in next, added to the batch by `push`.
+    fn next_packet(&mut self) -> &mut Rdma {
+        &mut self.packets[self.lens.len()]
+    }
+
+    /// Adds the packet returned by `next_packet`, `len` bytes of it are sent.
+    fn push(&mut self, len: usize) {
+        self.lens.push(len);
+    }
+
+    fn len(&self) -> usize {
+        self.lens.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lens.is_empty()
+    }
+
+    fn is_full(&self) -> bool {
+        self.lens.len() == self.packets.len()
+    }
+
+    fn clear(&mut self) {
+        self.lens.clear();
+    }
+
+    fn packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.packets
+            .iter()
+            .zip(&self.lens)
+            .map(|(packet, &len)| &bytemuck::bytes_of(packet)[..len])
+    }
+}
+
+/// Send packets to `addr`, batched into as few `sendmmsg` calls as possible.
+#[cfg(target_os = "linux")]
+fn send_packets(socket: &UdpSocket, batch: &PacketBatch, addr: SocketAddr) -> io::Result<()> {
+    use nix::sys::socket::{sendmmsg, ControlMessage, MsgFlags, MultiHeaders, SockaddrIn};
+    use std::{io::IoSlice, os::fd::AsRawFd};
+
+    let SocketAddr::V4(addr_v4) = addr else {
+        for packet in batch.packets() {
+            socket.send_to(packet, addr)?;
+        }
+        return Ok(());
+    };
+
+    let packets: Vec<&[u8]> = batch.packets().collect();
+    let mut remaining = &packets[..];
+    while !remaining.is_empty() {
+        let slices: Vec<[IoSlice; 1]> = remaining
+            .iter()
+            .map(|packet| [IoSlice::new(packet)])
+            .collect();
+        let addrs = vec![Some(SockaddrIn::from(addr_v4)); remaining.len()];
+        let cmsgs: [ControlMessage; 0] = [];
+        let mut headers = MultiHeaders::<SockaddrIn>::preallocate(remaining.len(), None);
+
+        let sent = sendmmsg(
+            socket.as_raw_fd(),
+            &mut headers,
+            &slices,
+            &addrs,
+            cmsgs,
+            MsgFlags::empty(),
+        )?
+        .count();
+        if sent == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        remaining = &remaining[sent..];
+    }
+
+    Ok(())
+}
+
+/// Send packets to `addr` one at a time, there is no `sendmmsg` on this platform.
+#[cfg(not(target_os = "linux"))]
+fn send_packets(socket: &UdpSocket, batch: &PacketBatch, addr: SocketAddr) -> io::Result<()> {
+    for packet in batch.packets() {
+        socket.send_to(packet, addr)?;
+    }
+
+    Ok(())
+}
+
+/// Batches each send worker can have queued before reads wait for it to catch up.
+const SEND_QUEUE_BATCHES: usize = 64;
+
+type ReadBatch = (PacketBatch, SocketAddr);
+
+/// Threads sending the RDMA packets of reads, so reading the next request from storage
+/// overlaps with sending the previous one. Only sending is spread over the workers, requests
+/// are still read from storage one at a time on the serving thread. Every client is sent to by
+/// the same worker, keeping its packets in order.
+struct SendPool {
+    queues: Vec<mpsc::SyncSender<ReadBatch>>,
+    /// Batches the workers are done with, emptied for reuse.
+    sent: mpsc::Receiver<PacketBatch>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl SendPool {
+    fn new(socket: &UdpSocket, workers: usize) -> io::Result<Self> {
+        let (recycle, sent) = mpsc::channel();
+        let mut pool = Self {
+            queues: Vec::with_capacity(workers),
+            sent,
+            threads: Vec::with_capacity(workers),
+        };
+        for _ in 0..workers {
+            let socket = socket.try_clone()?;
+            let recycle = recycle.clone();
+            let (queue, batches) = mpsc::sync_channel::<ReadBatch>(SEND_QUEUE_BATCHES);
+            pool.queues.push(queue);
+            pool.threads.push(thread::spawn(move || {
+                for (mut batch, addr) in batches {
+                    if let Err(err) = send_packets(&socket, &batch, addr) {
+                        warn!("Failed to reply with UDPBD_CMD_READ_RDMA to {addr}: {err}");
+                    }
+                    batch.clear();
+                    // the server may be gone already at shutdown
+                    let _ = recycle.send(batch);
+                }
+            }));
+        }
+
+        Ok(pool)
+    }
+
+    /// A batch a worker has finished sending, if there is one.
+    fn recycled(&self) -> Option<PacketBatch> {
+        self.sent.try_recv().ok()
+    }
+
+    fn send(&self, batch: PacketBatch, addr: SocketAddr) {
+        let mut hasher = DefaultHasher::new();
+        addr.hash(&mut hasher);
+        let queue = &self.queues[hasher.finish() as usize % self.queues.len()];
+
+        if queue.send((batch, addr)).is_err() {
+            warn!("Failed to reply with UDPBD_CMD_READ_RDMA to {addr}: send worker exited");
+        }
+    }
+
+    /// Waits for the queued packets to be sent.
+    fn finish(self) {
+        drop(self.queues);
+        for thread in self.threads {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// An empty batch for the packets of a read, reusing one sent earlier if there is one.
+fn spare_batch(spare: &mut Vec<PacketBatch>, send_pool: Option<&SendPool>) -> PacketBatch {
+    spare
+        .pop()
+        .or_else(|| send_pool.and_then(SendPool::recycled))
+        .unwrap_or_else(PacketBatch::new)
+}
+
+/// Number of blocks each RDMA packet of a read carries. `None` if a packet can't carry any
+/// blocks, the read would never finish.
+fn packet_block_counts(blocks: u32, blocks_per_packet: u16) -> Option<impl Iterator<Item = u16>> {
+    if blocks_per_packet == 0 {
+        return None;
+    }
+
+    let per_packet = u32::from(blocks_per_packet);
+    Some(
+        (0..blocks.div_ceil(per_packet))
+            .map(move |packet| (blocks - packet * per_packet).min(per_packet) as u16),
+    )
+}
+
+/// Header of the UDPBD_CMD_WRITE_DONE reply to the write sequence that `last` completed.
+///
+/// Packets of a sequence are numbered in the order they're sent, the write request is packet 0
+/// and its RDMA packets follow from 1, so the reply is numbered after the last RDMA packet, or
+/// after the request itself if there is no data.
+fn write_done_header(last: Header) -> Header {
+    Header::new_with_raw_value(0)
+        .with_command(Command::WriteDone)
+        .with_command_id(last.command_id())
+        .with_command_pkt(last.command_pkt().wrapping_add(1))
+}
+
+/// Progress of the current UDPBD_CMD_WRITE sequence.
+#[derive(Default)]
+struct WriteSession {
+    /// Byte offset the next RDMA packet is written to.
+    offset: u64,
+    size_left: usize,
+    rdma_valid: bool,
+    result: i32,
+    /// `command_pkt` the next RDMA packet must carry.
+    next_pkt: u8,
+}
+
+impl WriteSession {
+    /// Account for `size` bytes of received RDMA data, returns whether the write is complete.
+    fn consume(&mut self, size: usize) -> bool {
+        match self.size_left.checked_sub(size) {
+            Some(new_size) => self.size_left = new_size,
+            None => {
+                warn!("write_size_left wraparound at 0");
+                self.size_left = 0;
+            }
+        }
+
+        self.size_left == 0
+    }
+
+    /// Check that RDMA packet `pkt` is the one expected next. Data after a missing or reordered
+    /// packet can't be placed, the rest of the write is dropped and it fails.
+    fn in_sequence(&mut self, pkt: u8) -> bool {
+        let expected = self.next_pkt;
+        self.next_pkt = pkt.wrapping_add(1);
+        if pkt == expected {
+            return true;
+        }
+
+        self.rdma_valid = false;
+        self.result = WRITE_RESULT_ERROR;
+        false
+    }
+}
+
+/// Consecutive failed reads after which library storage is considered unavailable.
+pub const DEFAULT_STORAGE_FAILURE_THRESHOLD: u32 = 8;
+/// How often reads are retried while library storage is unavailable.
+pub const DEFAULT_STORAGE_BACKOFF: Duration = Duration::from_secs(2);
+
+/// How the server listens and serves the volume.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    pub vexfat: VexFatConfig,
+    /// Local address to listen on.
+    pub bind: Ipv4Addr,
+    pub port: u16,
+    /// Block shift used until the first read request picks one.
+    pub block_shift: u8,
+    /// DSCP value to mark outgoing packets with.
+    pub dscp: Option<u8>,
+    /// MTU of the network, RDMA packets are sized to fit.
+    pub mtu: u16,
+    /// Size of the socket receive buffer, the OS default if not set.
+    pub recv_buffer_size: Option<usize>,
+    /// Size of the socket send buffer, the OS default if not set.
+    pub send_buffer_size: Option<usize>,
+    /// Threads sending the replies to reads, 0 sends them from the thread handling requests.
+    /// Storage is read from the thread handling requests either way.
+    pub send_workers: usize,
+    /// Reread and compare every block sent.
+    pub verify_reads: bool,
+    /// Pin the block size to 32 bytes.
+    pub safe_mode: bool,
+    /// Accept commands on stdin to pause and resume serving clients.
+    pub interactive: bool,
+    pub discovery_reply: DiscoveryReply,
+    /// Refuse writes while free space drops below this many bytes.
+    pub min_free_space: Option<u64>,
+    pub storage_failure_threshold: u32,
+    pub storage_backoff: Duration,
+    /// Serve Prometheus metrics over HTTP on this address.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Advertise the server on the LAN over mDNS, only available with the mdns feature.
+    pub advertise: bool,
+    /// Broadcast the server's presence on this interval.
+    pub beacon_interval: Option<Duration>,
+    /// Only answer clients in these ranges, every client is answered if empty.
+    pub allow: Vec<Ipv4Net>,
+}
+
+impl ServerConfig {
+    /// Serves the volume on all interfaces on the UDPBD port, as the command line does by default.
+    pub fn new(vexfat: VexFatConfig) -> Self {
+        Self {
+            vexfat,
+            bind: Ipv4Addr::UNSPECIFIED,
+            port: UDPBD_PORT,
+            block_shift: DEFAULT_BLOCK_SHIFT,
+            dscp: None,
+            mtu: DEFAULT_MTU,
+            recv_buffer_size: None,
+            send_buffer_size: None,
+            send_workers: 0,
+            verify_reads: false,
+            safe_mode: false,
+            interactive: false,
+            discovery_reply: DiscoveryReply::Auto,
+            min_free_space: None,
+            storage_failure_threshold: DEFAULT_STORAGE_FAILURE_THRESHOLD,
+            storage_backoff: DEFAULT_STORAGE_BACKOFF,
+            metrics_addr: None,
+            advertise: false,
+            beacon_interval: None,
+            allow: Vec::new(),
+        }
+    }
+
+    pub fn bind(mut self, bind: Ipv4Addr) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = port;
+        self
+    }
+
+    pub fn block_shift(mut self, block_shift: u8) -> Self {
+        self.block_shift = block_shift;
+        self
+    }
+}
+
+/// Tells a single failed read apart from library storage that went away, e.g. a dropped NAS
+/// mount, so the latter is reported once instead of for every packet.
+struct StorageHealth {
+    failure_threshold: u32,
+    backoff: Duration,
+    consecutive_failures: u32,
+    unavailable_at: Option<Instant>,
+}
+
+impl StorageHealth {
+    fn new(failure_threshold: u32, backoff: Duration) -> Self {
+        Self {
+            failure_threshold,
+            backoff,
+            consecutive_failures: 0,
+            unavailable_at: None,
+        }
+    }
+
+    /// Records a failed read, returns whether the failure should still be logged.
+    fn failed(&mut self) -> bool {
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+
+        if self.unavailable_at.is_some() {
+            self.unavailable_at = Some(Instant::now());
+            return false;
+        }
+
+        if self.consecutive_failures >= self.failure_threshold {
+            error!(
+                "Library storage appears unavailable after {} consecutive read failures, retrying every {} ms",
+                self.consecutive_failures,
+                self.backoff.as_millis()
+            );
+            self.unavailable_at = Some(Instant::now());
+        }
+        true
+    }
+
+    fn succeeded(&mut self) {
+        if self.unavailable_at.take().is_some() {
+            info!("Library storage is readable again");
+        }
+        self.consecutive_failures = 0;
+    }
+
+    fn is_unavailable(&self) -> bool {
+        self.unavailable_at.is_some()
+    }
+
+    /// While storage is unavailable, reads are only attempted once per backoff period.
+    fn should_serve(&self) -> bool {
+        self.unavailable_at
+            .is_none_or(|at| at.elapsed() >= self.backoff)
+    }
+}
+
+/// Sector ranges already reported as unreadable, merged as they grow so a region is reported
+/// once however many reads run into it.
+#[derive(Default)]
+struct SectorRanges(BTreeMap<u64, u64>);
+
+impl SectorRanges {
+    /// Records `sectors`, returns whether they don't touch any range recorded before.
+    fn insert(&mut self, sectors: Range<u64>) -> bool {
+        let touching: Vec<(u64, u64)> = self
+            .0
+            .range(..=sectors.end)
+            .filter(|(_, &end)| end >= sectors.start)
+            .map(|(&start, &end)| (start, end))
+            .collect();
+        let start = touching
+            .iter()
+            .fold(sectors.start, |start, &(other, _)| start.min(other));
+        let end = touching
+            .iter()
+            .fold(sectors.end, |end, &(_, other)| end.max(other));
+        for (other, _) in &touching {
+            self.0.remove(other);
+        }
+        self.0.insert(start, end);
+
+        touching.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Transfer counters since the server started, to diagnose slow transfers.
+#[derive(Clone, Debug)]
+pub struct Stats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// RDMA and write done packets sent to clients.
+    pub packets_sent: u64,
+    pub read_requests: u64,
+    pub write_requests: u64,
+    window_started: Instant,
+    window_bytes: u64,
+    last_window_bytes: u64,
+    /// When each client last read or wrote.
+    clients: HashMap<IpAddr, Instant>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            bytes_read: 0,
+            bytes_written: 0,
+            packets_sent: 0,
+            read_requests: 0,
+            write_requests: 0,
+            window_started: Instant::now(),
+            window_bytes: 0,
+            last_window_bytes: 0,
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl Stats {
+    /// Records a read or write request from `ip`.
+    fn client_seen(&mut self, ip: IpAddr) {
+        let now = Instant::now();
+        self.clients
+            .retain(|_, seen| now.duration_since(*seen) < CLIENT_IDLE_TIMEOUT);
+        self.clients.insert(ip, now);
+    }
+
+    /// Clients that read or wrote within the idle timeout.
+    pub fn active_clients(&self) -> usize {
+        self.clients
+            .values()
+            .filter(|seen| seen.elapsed() < CLIENT_IDLE_TIMEOUT)
+            .count()
+    }
+
+    /// Counts `bytes` read or written towards the throughput of the current second.
+    fn transferred(&mut self, bytes: u64) {
+        self.roll_window();
+        self.window_bytes += bytes;
+    }
+
+    fn roll_window(&mut self) {
+        let elapsed = self.window_started.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+
+        // nothing was transferred in the seconds skipped over
+        self.last_window_bytes = if elapsed < Duration::from_secs(2) {
+            self.window_bytes
+        } else {
+            0
+        };
+        self.window_bytes = 0;
+        self.window_started = Instant::now();
+    }
+
+    /// Bytes read and written in the last full second.
+    pub fn throughput(&self) -> u64 {
+        match self.window_started.elapsed() {
+            elapsed if elapsed < Duration::from_secs(1) => self.last_window_bytes,
+            elapsed if elapsed < Duration::from_secs(2) => self.window_bytes,
+            _ => 0,
+        }
+    }
+}
+
+/// How long a client can stay quiet before its write session and block geometry are dropped.
+const CLIENT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Write session of a single client, kept apart so two consoles writing at once don't clobber
+/// each other's accounting.
+struct ClientWrite {
+    session: WriteSession,
+    last_seen: Instant,
+}
+
+/// Write session of `addr`, evicting those of clients idle for longer than the timeout.
+fn client_write(
+    clients: &mut HashMap<SocketAddr, ClientWrite>,
+    addr: SocketAddr,
+) -> &mut WriteSession {
+    let now = Instant::now();
+    clients.retain(|client, write| {
+        *client == addr || now.duration_since(write.last_seen) < CLIENT_IDLE_TIMEOUT
+    });
+
+    let write = clients.entry(addr).or_insert_with(|| ClientWrite {
+        session: WriteSession::default(),
+        last_seen: now,
+    });
+    if now.duration_since(write.last_seen) >= CLIENT_IDLE_TIMEOUT && write.session.size_left > 0 {
+        warn!(
+            "Discarding the write from {addr} idle for over {}s with {} bytes outstanding",
+            CLIENT_IDLE_TIMEOUT.as_secs(),
+            write.session.size_left
+        );
+        write.session = WriteSession::default();
+    }
+    write.last_seen = now;
+
+    &mut write.session
+}
+
+/// Block geometry of a single client, each picks its block size from its own request sizes.
+struct ClientRead {
+    geometry: BlockGeometry,
+    last_seen: Instant,
+}
+
+/// Block geometry of `addr`, starting out as `initial`, evicting those of clients idle for
+/// longer than the timeout.
+fn client_read<'a>(
+    clients: &'a mut HashMap<SocketAddr, ClientRead>,
+    addr: SocketAddr,
+    initial: &BlockGeometry,
+) -> &'a mut BlockGeometry {
+    let now = Instant::now();
+    clients.retain(|client, read| {
+        *client == addr || now.duration_since(read.last_seen) < CLIENT_IDLE_TIMEOUT
+    });
+
+    let read = clients.entry(addr).or_insert_with(|| ClientRead {
+        geometry: initial.clone(),
+        last_seen: now,
+    });
+    read.last_seen = now;
+
+    &mut read.geometry
+}
+
+/// How long `run` waits for a packet before checking whether it should shut down.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the figures served by the metrics endpoint are brought up to date.
+const METRICS_PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often free space on the write target is rechecked.
+const FREE_SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default interval between presence broadcasts in beacon mode.
+pub const DEFAULT_BEACON_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Broadcasts an unsolicited InfoReply on an interval, so clients can find the server
+/// without asking. Only sent between packets, so never more often than `run` wakes up.
+struct Beacon {
+    interval: Duration,
+    sent_at: Option<Instant>,
+}
+
+impl Beacon {
+    /// Returns whether the next broadcast is due, and if so counts it as sent.
+    fn due(&mut self) -> bool {
+        if !self.sent_at.is_none_or(|at| at.elapsed() >= self.interval) {
+            return false;
+        }
+        self.sent_at = Some(Instant::now());
+
+        true
+    }
+}
+
+/// Refuses writes while free space on the write target is below a minimum.
+struct FreeSpaceCheck {
+    path: PathBuf,
+    min_free_space: u64,
+    checked_at: Option<Instant>,
+    refusing: bool,
+}
+
+impl FreeSpaceCheck {
+    /// Rechecks free space if due, returns whether writes are allowed.
+    fn allows_writes(&mut self) -> bool {
+        if !self
+            .checked_at
+            .is_none_or(|at| at.elapsed() >= FREE_SPACE_CHECK_INTERVAL)
+        {
+            return !self.refusing;
+        }
+        self.checked_at = Some(Instant::now());
+
+        let available = match fs2::available_space(&self.path) {
+            Ok(available) => available,
+            Err(err) => {
+                warn!(
+                    "Failed to check free space on {}: {err}",
+                    self.path.display()
+                );
+                return !self.refusing;
+            }
+        };
+
+        let refusing = available < self.min_free_space;
+        if refusing {
+            error!(
+                "Only {} MiB free on {}, below the {} MiB minimum, refusing writes",
+                available / 1024 / 1024,
+                self.path.display(),
+                self.min_free_space / 1024 / 1024
+            );
+        } else if available < self.min_free_space.saturating_mul(2) {
+            warn!(
+                "Only {} MiB free on {}, writes are refused below {} MiB",
+                available / 1024 / 1024,
+                self.path.display(),
+                self.min_free_space / 1024 / 1024
+            );
+        } else if self.refusing {
+            info!(
+                "Free space on {} recovered, accepting writes",
+                self.path.display()
+            );
+        }
+        self.refusing = refusing;
+
+        !refusing
+    }
+}
+
+pub struct Server {
+    block_device: VexFat,
+    socket: UdpSocket,
+    /// Receives broadcast discovery requests when `socket` is bound to a specific address.
+    discovery_socket: Option<UdpSocket>,
+    /// Emptied batches ready for the packets of the next read.
+    spare_batches: Vec<PacketBatch>,
+    writes: HashMap<SocketAddr, ClientWrite>,
+    /// Block geometry new clients start out with, follows from the MTU and block size options.
+    block_geometry: BlockGeometry,
+    reads: HashMap<SocketAddr, ClientRead>,
+    send_pool: Option<SendPool>,
+    verify_reads: bool,
+    paused_clients: PausedClients,
+    discovery_reply: DiscoveryReply,
+    free_space: Option<FreeSpaceCheck>,
+    beacon: Option<Beacon>,
+    allow: Vec<Ipv4Net>,
+    /// Disallowed clients already reported, to log each only once.
+    refused_clients: HashSet<IpAddr>,
+    /// Mapped files already reported as unreadable, to log each only once.
+    unreadable_files: HashSet<String>,
+    /// Sectors reported as unreadable without a mapped file to blame, to log each region once.
+    unreadable_sectors: SectorRanges,
+    storage: StorageHealth,
+    stats: Stats,
+    /// Where stats are published for the metrics endpoint, if it is enabled.
+    metrics: Option<SharedMetrics>,
+    metrics_published_at: Instant,
+    /// Withdrawn when dropped at shutdown.
+    #[cfg(feature = "mdns")]
+    advertisement: Option<Advertisement>,
+    /// Stops `run` once set.
+    shutdown: Arc<AtomicBool>,
+    /// Starts a rescan of root once set.
+    rescan: Arc<AtomicBool>,
+    /// Rescan mapping root on its own thread while the old volume is still served.
+    rescanning: Option<thread::JoinHandle<anyhow::Result<Volume>>>,
+    /// How the volume was mapped, reused when rescanning.
+    vexfat_config: VexFatConfig,
+}
+
+impl Server {
+    pub fn new(config: &ServerConfig) -> anyhow::Result<Self> {
+        if config.port != UDPBD_PORT {
+            warn!(
+                "Listening on port {}, the PS2 only looks for the server on port {UDPBD_PORT}",
+                config.port
+            );
+        }
+
+        // Linux only delivers broadcasts to sockets bound to the wildcard address, discovery
+        // gets a socket of its own sharing the port
+        let discovery = cfg!(target_os = "linux")
+            && !config.bind.is_unspecified()
+            && !config.bind.is_loopback();
+        let addr = SocketAddr::new(IpAddr::V4(config.bind), config.port);
+        let socket = bind_udp(addr, discovery)
+            .with_context(|| format!("Failed to bind UDP socket to {addr}"))?;
+
+        socket
+            .set_read_timeout(Some(SHUTDOWN_POLL_INTERVAL))
+            .context("Failed to set UDP socket read timeout")?;
+
+        #[cfg(target_os = "linux")]
+        let discovery_socket = if discovery {
+            let port = socket
+                .local_addr()
+                .context("Failed to get UDP socket address")?
+                .port();
+            match open_discovery_socket(config.bind, port) {
+                Ok(discovery_socket) => Some(discovery_socket),
+                Err(err) => {
+                    warn!(
+                        "Bound to {}, broadcast discovery requests from the PS2 won't be received: {err:#}",
+                        config.bind
+                    );
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        #[cfg(not(target_os = "linux"))]
+        let discovery_socket = None;
+        socket
+            .set_broadcast(true)
+            .context("Failed to enable broadcast on UDP socket")?;
+
+        if let Some(dscp) = config.dscp {
+            // DSCP lives in the upper six bits of the TOS byte
+            match socket2::SockRef::from(&socket).set_tos(u32::from(dscp) << 2) {
+                Ok(()) => info!("Marking outgoing packets with DSCP {dscp}"),
+                Err(err) => warn!("Failed to set DSCP {dscp} on UDP socket, ignoring: {err}"),
+            }
+        }
+
+        let sock_ref = socket2::SockRef::from(&socket);
+        if let Some(size) = config.recv_buffer_size {
+            sock_ref.set_recv_buffer_size(size).with_context(|| {
+                format!("Failed to set UDP socket receive buffer to {size} bytes")
+            })?;
+            // the OS may clamp or, like Linux, double the requested size
+            match sock_ref.recv_buffer_size() {
+                Ok(granted) => info!("Receive buffer is {granted} bytes, requested {size}"),
+                Err(err) => warn!("Failed to get UDP socket receive buffer size: {err}"),
+            }
+        }
+        if let Some(size) = config.send_buffer_size {
+            sock_ref
+                .set_send_buffer_size(size)
+                .with_context(|| format!("Failed to set UDP socket send buffer to {size} bytes"))?;
+            match sock_ref.send_buffer_size() {
+                Ok(granted) => info!("Send buffer is {granted} bytes, requested {size}"),
+                Err(err) => warn!("Failed to get UDP socket send buffer size: {err}"),
+            }
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            use nix::sys::socket::{setsockopt, sockopt::Ipv4PacketInfo};
+            use std::os::fd::AsRawFd;
+
+            setsockopt(socket.as_raw_fd(), Ipv4PacketInfo, &true)
+                .context("Failed to enable packet info on UDP socket")?;
+        }
+
+        let send_pool = match config.send_workers {
+            0 => None,
+            workers => {
+                info!("Sending read replies from {workers} worker threads");
+                Some(SendPool::new(&socket, workers).context("Failed to start send workers")?)
+            }
+        };
+
+        let vexfat = VexFat::new(&config.vexfat)?;
+
+        let mut block_geometry = BlockGeometry::new(vexfat.sector_size());
+        block_geometry.set_mtu(config.mtu);
+        if config.safe_mode {
+            block_geometry.max_block_shift = SAFE_MODE_BLOCK_SHIFT;
+            block_geometry.set_block_shift(SAFE_MODE_BLOCK_SHIFT);
+            info!("Safe mode enabled, block size pinned to 32 bytes");
+        } else {
+            block_geometry.set_block_shift(config.block_shift.min(block_geometry.max_block_shift));
+        }
+
+        let mut server = Server {
+            block_device: vexfat,
+            socket,
+            discovery_socket,
+            spare_batches: Vec::new(),
+            writes: HashMap::new(),
+            block_geometry,
+            reads: HashMap::new(),
+            send_pool,
+            verify_reads: config.verify_reads,
+            paused_clients: PausedClients::default(),
+            discovery_reply: config.discovery_reply,
+            free_space: config.min_free_space.map(|min_free_space| FreeSpaceCheck {
+                // writes land in the overlay if there is one
+                path: config
+                    .vexfat
+                    .overlay
+                    .clone()
+                    .unwrap_or_else(|| config.vexfat.root.clone()),
+                min_free_space,
+                checked_at: None,
+                refusing: false,
+            }),
+            allow: config.allow.clone(),
+            refused_clients: HashSet::new(),
+            beacon: config.beacon_interval.map(|interval| Beacon {
+                interval,
+                sent_at: None,
+            }),
+            unreadable_files: HashSet::new(),
+            unreadable_sectors: SectorRanges::default(),
+            storage: StorageHealth::new(config.storage_failure_threshold, config.storage_backoff),
+            stats: Stats::default(),
+            metrics: None,
+            metrics_published_at: Instant::now(),
+            #[cfg(feature = "mdns")]
+            advertisement: None,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            rescan: Arc::new(AtomicBool::new(false)),
+            rescanning: None,
+            vexfat_config: config.vexfat.clone(),
+        };
+
+        if server.verify_reads {
+            info!("Read verification enabled, every block is read twice");
+        }
+
+        if config.interactive {
+            console::spawn(server.paused_clients.clone());
+        }
+
+        if let Some(free_space) = &mut server.free_space {
+            free_space.allows_writes();
+        }
+
+        if let Some(addr) = config.metrics_addr {
+            let metrics = Arc::new(Mutex::new(server.metrics()));
+            metrics::spawn(addr, metrics.clone())
+                .with_context(|| format!("Failed to serve metrics on {addr}"))?;
+            server.metrics = Some(metrics);
+        }
+
+        #[cfg(feature = "mdns")]
+        if config.advertise {
+            // port 0 picks a free port, advertise the one actually bound
+            let port = server
+                .socket
+                .local_addr()
+                .map_or(config.port, |addr| addr.port());
+            match Advertisement::start(config.bind, port) {
+                Ok(advertisement) => server.advertisement = Some(advertisement),
+                Err(err) => warn!("Not advertising the server over mDNS: {err:#}"),
+            }
+        }
+        #[cfg(not(feature = "mdns"))]
+        if config.advertise {
+            warn!("Not advertising the server over mDNS: built without the mdns feature");
+        }
+
+        Ok(server)
+    }
+
+    /// Volume being served.
+    pub fn vexfat(&self) -> &VexFat {
+        &self.block_device
+    }
+
+    /// Transfers served so far, for a frontend to poll.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    fn metrics(&self) -> Metrics {
+        Metrics {
+            stats: self.stats.clone(),
+            volume_size: u64::from(self.block_device.sector_count())
+                * u64::from(self.block_device.sector_size()),
+        }
+    }
+
+    /// Setting the returned flag makes `run` return after the packet it is handling.
+    pub fn shutdown_handle(&self) -> Arc<AtomicBool> {
+        self.shutdown.clone()
+    }
+
+    /// Setting the returned flag makes the server rescan root. Root is mapped on another thread
+    /// and the new volume is served once it's ready.
+    pub fn rescan_handle(&self) -> Arc<AtomicBool> {
+        self.rescan.clone()
+    }
+
+    /// Serves packets until shutdown is requested. Transient receive errors are skipped,
+    /// fatal ones are returned.
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        let mut buf = [0u8; UDP_MAX_PAYLOAD];
+        match self.socket.local_addr() {
+            Ok(addr) => {
+                info!(event = "startup", port = addr.port(); "Server running on port {}", addr.port())
+            }
+            Err(err) => warn!("Failed to get the local address of the UDP socket: {err}"),
+        }
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            // copying the stats every packet would cost more than scrapes ever need
+            if let Some(metrics) = &self.metrics {
+                if self.metrics_published_at.elapsed() >= METRICS_PUBLISH_INTERVAL {
+                    *metrics.lock().unwrap() = self.metrics();
+                    self.metrics_published_at = Instant::now();
+                }
+            }
+            if self.beacon.as_mut().is_some_and(Beacon::due) {
+                self.send_beacon();
+            }
+
+            // a rescan asked for while one is running starts once it is done
+            if self.rescanning.is_none() && self.rescan.swap(false, Ordering::SeqCst) {
+                info!("Rescanning {}", self.vexfat_config.root.display());
+                let config = self.vexfat_config.clone();
+                self.rescanning = Some(thread::spawn(move || VexFat::scan(&config)));
+            }
+            if let Some(rescanning) = self
+                .rescanning
+                .take_if(|rescanning| rescanning.is_finished())
+            {
+                let replaced = match rescanning.join() {
+                    Ok(volume) => volume
+                        .and_then(|volume| self.block_device.replace(volume, &self.vexfat_config)),
+                    Err(_) => Err(anyhow::anyhow!("the rescan thread panicked")),
+                };
+                match replaced {
+                    Ok(()) => {
+                        self.writes.clear();
+                        self.unreadable_files.clear();
+                        self.unreadable_sectors.clear();
+                        info!(
+                            "Volume is now {} sectors, clients must query the server again to see the changes",
+                            self.block_device.sector_count()
+                        );
+                    }
+                    Err(err) => error!("Rescan failed, still serving the old volume: {err:#}"),
+                }
+            }
+
+            let from_discovery = match &self.discovery_socket {
+                Some(discovery_socket) => match poll_sockets(&self.socket, discovery_socket) {
+                    Ok(Some(from_discovery)) => from_discovery,
+                    Ok(None) => continue,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(err) => return Err(err).context("Failed to wait for packets"),
+                },
+                None => false,
+            };
+            let socket = match &self.discovery_socket {
+                Some(discovery_socket) if from_discovery => discovery_socket,
+                _ => &self.socket,
+            };
+            let (len, addr, broadcast) = match recv_packet(socket, &mut buf[..]) {
+                Ok(packet) => packet,
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) =>
+                {
+                    continue
+                }
+                // on Windows a reply to a client that went away surfaces here on the next receive
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => {
+                    debug!("Ignoring receive error: {err}");
+                    continue;
+                }
+                Err(err) => return Err(err).context("Failed to receive packet"),
+            };
+            if from_discovery && !(broadcast && is_info_request(&buf[..len])) {
+                // sent to another address of the interface, not meant for this server
+                debug!("Ignoring packet from {addr} on the discovery socket");
+                continue;
+            }
+            if !self.allows(addr) {
+                continue;
+            }
+            self.handle_packet(&buf[..len], addr, broadcast);
+        }
+
+        info!("Shutting down");
+        if let Some(rescanning) = self.rescanning.take() {
+            // dropping the unfinished volume cleans up after it
+            info!("Waiting for the rescan to finish");
+            let _ = rescanning.join();
+        }
+        if let Some(send_pool) = self.send_pool.take() {
+            send_pool.finish();
+        }
+        #[cfg(feature = "mdns")]
+        if self.advertisement.take().is_some() {
+            info!("Withdrew mDNS advertisement");
+        }
+        info!(
+            "Served {} reads ({} MiB) and {} writes ({} MiB) in {} packets",
+            self.stats.read_requests,
+            self.stats.bytes_read / 1024 / 1024,
+            self.stats.write_requests,
+            self.stats.bytes_written / 1024 / 1024,
+            self.stats.packets_sent
+        );
+        if let Some((hits, misses)) = self.block_device.cache_stats() {
+            info!("Sector cache: {hits} hits, {misses} misses");
+        }
+        for (addr, write) in &self.writes {
+            if write.session.size_left > 0 {
+                // writes are applied as each RDMA packet arrives, there is nothing buffered to flush
+                warn!(
+                    "Abandoning the write in progress from {addr} with {} bytes outstanding",
+                    write.session.size_left
+                );
+            }
+        }
 
-use crate::{
-    protocol::{
-        BlockType, Command, Header, InfoReply, InfoRequest, Rdma, ReadWriteRequest, WriteReply,
-        RDMA_MAX_PAYLOAD, UDPBD_PORT, UDP_MAX_PAYLOAD,
-    },
-    vexfat::VexFat,
-    Args,
-};
+        Ok(())
+    }
 
-pub struct Server {
-    block_device: VexFat,
-    socket: UdpSocket,
-    write_size_left: usize,
-    write_rdma_valid: bool,
-}
+    /// Whether packets from `addr` are in the allowed ranges, reporting each refused client once.
+    fn allows(&mut self, addr: SocketAddr) -> bool {
+        if self.allow.is_empty() || self.allow.iter().any(|net| net.contains(addr.ip())) {
+            return true;
+        }
 
-impl Server {
-    pub fn new(args: &Args) -> anyhow::Result<Self> {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), UDPBD_PORT);
-        let socket = UdpSocket::bind(addr).context("Failed to create UDP socket")?;
-        socket
-            .set_broadcast(true)
-            .context("Failed to enable broadcast on UDP socket")?;
+        if self.refused_clients.insert(addr.ip()) {
+            warn!("Dropping packets from {addr}, not in the allowed ranges");
+        } else {
+            debug!("Dropping packet from {addr}, not in the allowed ranges");
+        }
+        false
+    }
 
-        let vexfat = VexFat::new(args);
+    /// Parses a received packet and dispatches it to the matching command handler.
+    /// Packets too short for their command are dropped.
+    pub fn handle_packet(&mut self, buf: &[u8], addr: SocketAddr, broadcast: bool) {
+        macro_rules! cast_buffer_as {
+            ($type:ty) => {
+                match buf.get(..size_of::<$type>()) {
+                    Some(bytes) => bytemuck::from_bytes::<$type>(bytes),
+                    None => {
+                        debug!(
+                            "Dropping {} byte packet from {addr}, too short for {}",
+                            buf.len(),
+                            stringify!($type)
+                        );
+                        return;
+                    }
+                }
+            };
+        }
 
-        let mut server = Server {
-            block_device: vexfat,
-            socket,
-            write_size_left: 0,
-            write_rdma_valid: false,
+        let header = cast_buffer_as!(Header);
+        match header.command() {
+            Ok(cmd) => match cmd {
+                Command::Info => {
+                    self.handle_cmd_info(cast_buffer_as!(InfoRequest), addr, broadcast)
+                }
+                Command::Read => self.handle_cmd_read(cast_buffer_as!(ReadWriteRequest), addr),
+                Command::Write => self.handle_cmd_write(cast_buffer_as!(ReadWriteRequest), addr),
+                Command::WriteRdma => {
+                    // RDMA packets only carry as much data as needed, pad them to the full size
+                    // but still require the block type describing that data
+                    let min_len = size_of::<Header>() + size_of::<BlockType>();
+                    if buf.len() < min_len {
+                        debug!(
+                            "Dropping {} byte packet from {addr}, too short for Rdma",
+                            buf.len()
+                        );
+                        return;
+                    }
+                    let mut req = Rdma::zeroed();
+                    let len = buf.len().min(size_of::<Rdma>());
+                    bytemuck::bytes_of_mut(&mut req)[..len].copy_from_slice(&buf[..len]);
+                    // the block type is untrusted, it can describe more data than was sent
+                    if req.block_type.blocks_size() > len - min_len {
+                        debug!(
+                            "Dropping {} byte packet from {addr}, too short for the {} bytes of blocks it describes",
+                            buf.len(),
+                            req.block_type.blocks_size()
+                        );
+                        return;
+                    }
+                    self.handle_cmd_write_rdma(&req, addr)
+                }
+                // replies of other servers on the network, answering them could start two
+                // servers bouncing packets off each other
+                Command::InfoReply | Command::ReadRdma | Command::WriteDone => {
+                    debug!("Ignoring {:?} from {addr}, only servers send it", cmd)
+                }
+            },
+            Err(cmd) => self.handle_unsupported(*header, cmd, addr, broadcast),
         };
-        server.block_device.set_block_shift(5); // 128b blocks
+    }
 
-        Ok(server)
+    /// Fails a command this server doesn't know right away, so the client doesn't wait for a
+    /// reply until it times out. The only reply every client understands without asking for it
+    /// is WRITE_DONE, it carries the error.
+    fn handle_unsupported(&mut self, header: Header, cmd: u5, addr: SocketAddr, broadcast: bool) {
+        if broadcast {
+            debug!("Ignoring unknown command {cmd} broadcast by {addr}");
+            return;
+        }
+
+        debug!("Failing unknown command {cmd} from {addr}");
+        self.send_write_done(write_done_header(header), RESULT_UNSUPPORTED, addr);
     }
 
-    pub fn run(&mut self) {
-        let mut buf = [0u8; UDP_MAX_PAYLOAD];
-        println!("Server running on port {}", UDPBD_PORT);
+    fn handle_cmd_info(&mut self, req: &InfoRequest, addr: SocketAddr, broadcast: bool) {
+        let kind = if broadcast { "broadcast" } else { "unicast" };
+        info!(event = "info_request", client:% = addr; "UDPBD_CMD_INFO from {addr} ({kind})");
 
-        loop {
-            let (_, addr) = self.socket.recv_from(&mut buf[..]).unwrap();
+        let broadcast_reply = match self.discovery_reply {
+            DiscoveryReply::Auto => broadcast,
+            DiscoveryReply::Unicast => false,
+            DiscoveryReply::Broadcast => true,
+        };
+        let reply_addr = if broadcast_reply {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), addr.port())
+        } else {
+            addr
+        };
 
-            macro_rules! cast_buffer_as {
-                ($type:ty) => {
-                    bytemuck::from_bytes::<$type>(&buf[..size_of::<$type>()])
-                };
-            }
+        let reply = self.info_reply(req.header.command_id());
+        let ser = bytemuck::bytes_of(&reply);
 
-            let header = cast_buffer_as!(Header);
-            match header.command() {
-                Ok(cmd) => match cmd {
-                    Command::Info => self.handle_cmd_info(cast_buffer_as!(InfoRequest), addr),
-                    Command::Read => self.handle_cmd_read(cast_buffer_as!(ReadWriteRequest), addr),
-                    Command::Write => self.handle_cmd_write(cast_buffer_as!(ReadWriteRequest)),
-                    Command::WriteRdma => self.handle_cmd_write_rdma(cast_buffer_as!(Rdma), addr),
-                    cmd => println!("Unexpected command: {cmd:?}"),
-                },
-                Err(cmd) => println!("Unknown command: {cmd}"),
-            };
+        if let Err(err) = self.socket.send_to(ser, reply_addr) {
+            warn!("Failed to reply with UDPBD_CMD_INFO_REPLY to {reply_addr}: {err}");
         }
     }
 
-    fn handle_cmd_info(&mut self, req: &InfoRequest, addr: SocketAddr) {
-        println!("UDPBD_CMD_INFO from {addr}");
-
-        let reply = InfoReply {
+    fn info_reply(&self, command_id: u3) -> InfoReply {
+        InfoReply {
             header: Header::new_with_raw_value(0)
                 .with_command(Command::InfoReply)
-                .with_command_id(req.header.command_id())
+                .with_command_id(command_id)
                 .with_command_pkt(1),
             sector_size: u32::from(self.block_device.sector_size()),
             sector_count: self.block_device.sector_count(),
-        };
-        let ser = bytemuck::bytes_of(&reply);
+        }
+    }
+
+    /// Broadcasts an InfoReply nobody asked for, announcing the server to the subnet.
+    fn send_beacon(&self) {
+        let reply = self.info_reply(u3::new(0));
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::BROADCAST), UDPBD_PORT);
 
-        if let Err(err) = self.socket.send_to(ser, addr) {
-            eprintln!("Failed to reply with UDPBD_CMD_INFO_REPLY to {addr}: {err}");
+        match self.socket.send_to(bytemuck::bytes_of(&reply), addr) {
+            Ok(_) => debug!("Broadcast presence beacon to {addr}"),
+            Err(err) => warn!("Failed to broadcast presence beacon to {addr}: {err}"),
         }
     }
 
@@ -95,125 +1500,1022 @@ impl Server {
             ..
         } = *req;
 
-        println!(
+        debug!(
+            event = "read", client:% = addr, sector = sector_nr, count = sector_count;
             "UDPBD_CMD_READ(cmdId={}, startSector={}, sectorCount={})",
             req.header.command_id(),
             sector_nr,
             sector_count
         );
 
-        self.block_device.set_block_shift_sectors(sector_count);
+        if sector_count == 0 {
+            // nothing to send, and no request size to pick a block size for
+            debug!("Ignoring UDPBD_CMD_READ of 0 sectors from {addr}");
+            return;
+        }
 
-        let mut reply = Rdma {
-            header: Header::new_with_raw_value(0)
-                .with_command(Command::ReadRdma)
-                .with_command_id(req.header.command_id())
-                .with_command_pkt(1),
-            block_type: BlockType::new_with_raw_value(0)
-                .with_block_shift(u4::new(self.block_device.block_shift)),
-            data: [0; RDMA_MAX_PAYLOAD],
-        };
+        if self.paused_clients.lock().unwrap().contains(&addr.ip()) {
+            debug!("Dropping UDPBD_CMD_READ from {addr}, client is paused");
+            return;
+        }
+
+        if !self.storage.should_serve() {
+            // the PS2 retries the read, by then storage may be back
+            return;
+        }
+
+        self.stats.read_requests += 1;
+        self.stats.client_seen(addr.ip());
+        let geometry = client_read(&mut self.reads, addr, &self.block_geometry);
+        geometry.set_block_shift_sectors(sector_count);
+        let BlockGeometry {
+            block_shift,
+            block_size,
+            blocks_per_packet,
+            blocks_per_socket,
+            ..
+        } = *geometry;
+
+        let mut header = Header::new_with_raw_value(0)
+            .with_command(Command::ReadRdma)
+            .with_command_id(req.header.command_id())
+            .with_command_pkt(1);
+        let block_type = BlockType::new_with_raw_value(0).with_block_shift(u4::new(block_shift));
+
+        let end_sector = u64::from(sector_nr) + u64::from(sector_count);
+        if end_sector > u64::from(self.block_device.sector_count()) {
+            debug!(
+                "UDPBD_CMD_READ from {addr} ends past the last sector {}, zero-filling the rest",
+                self.block_device.sector_count() - 1
+            );
+        }
 
         let mut seeked = true;
         if let Err(err) = self.block_device.seek(sector_nr) {
-            eprintln!("Failed to seek block device in UDPBD_CMD_READ for {addr}: {err}");
+            error!(
+                event = "read_error", client:% = addr, sector = sector_nr;
+                "Failed to seek block device in UDPBD_CMD_READ for {addr}: {err}"
+            );
             seeked = false;
         }
 
-        let mut blocks_left = sector_count * self.block_device.blocks_per_socket;
-        while blocks_left > 0 {
-            let block_count = if blocks_left > self.block_device.blocks_per_packet {
-                self.block_device.blocks_per_packet
-            } else {
-                blocks_left
-            };
-            reply.block_type = reply.block_type.with_block_count(u9::new(block_count));
-            blocks_left -= block_count;
+        let start_offset = u64::from(sector_nr) * u64::from(self.block_device.sector_size());
+        let mut offset = start_offset;
+        let blocks = u32::from(sector_count) * u32::from(blocks_per_socket);
+        let Some(packets) = packet_block_counts(blocks, blocks_per_packet) else {
+            error!(
+                "Aborting UDPBD_CMD_READ for {addr}, block size {block_size} leaves no room for blocks in a packet"
+            );
+            return;
+        };
+
+        let mut batch = spare_batch(&mut self.spare_batches, self.send_pool.as_ref());
+        for block_count in packets {
+            // the data of every packet is overwritten before it is sent, only set the headers
+            let packet = batch.next_packet();
+            packet.header = header;
+            packet.block_type = block_type.with_block_count(u9::new(block_count));
 
             // read data from file
-            let size = usize::from(block_count * self.block_device.block_size);
-            let buf = &mut reply.data[..size];
+            let size = usize::from(block_count * block_size);
+            let buf = &mut packet.data[..size];
             if seeked {
-                if let Err(err) = self.block_device.read(buf) {
-                    eprintln!(
-                        "Failed to read block device in UDPBD_CMD_READ for {addr}, zeroing: {err}"
-                    );
-                    reply.data = [0; RDMA_MAX_PAYLOAD];
+                match self.block_device.read_padded(buf) {
+                    Ok(()) if self.verify_reads => {
+                        self.storage.succeeded();
+                        match self.block_device.verify(buf) {
+                            Ok(true) => {}
+                            Ok(false) => error!(
+                                "Read verification mismatch in UDPBD_CMD_READ for {addr} at sector {sector_nr}, host storage returned inconsistent data"
+                            ),
+                            Err(err) => error!(
+                                "Failed to reread block device in UDPBD_CMD_READ for {addr}: {err}"
+                            ),
+                        }
+                    }
+                    Ok(()) => self.storage.succeeded(),
+                    Err(err) => {
+                        // only the part being sent, the rest of the buffer is never looked at
+                        buf.fill(0);
+                        // a single unreadable file says nothing about the storage as a whole, but
+                        // once storage is gone there is no point looking at files one by one
+                        let file_problem = !self.storage.is_unavailable()
+                            && self.report_unreadable_file(offset, size, &err);
+                        if !file_problem && self.storage.failed() {
+                            error!(
+                                "Failed to read block device in UDPBD_CMD_READ for {addr}, zeroing: {err}"
+                            );
+                        }
+                    }
                 }
+            } else {
+                // don't resend what an earlier read left in the packet
+                buf.fill(0);
             }
+            offset += size as u64;
+
+            // send packets to PS2 a batch at a time
+            batch.push(size_of::<Header>() + size_of::<BlockType>() + size);
+            if batch.is_full() {
+                self.send_read_batch(&mut batch, addr);
+            }
+
+            header = header.with_command_pkt(header.command_pkt().wrapping_add(1));
+        }
+        self.send_read_batch(&mut batch, addr);
+        self.spare_batches.push(batch);
+
+        // catch block size arithmetic going wrong before it turns into corruption on the PS2
+        let sent = offset - start_offset;
+        self.stats.bytes_read += sent;
+        self.stats.transferred(sent);
+        let requested = u64::from(sector_count) * u64::from(self.block_device.sector_size());
+        debug_assert_eq!(
+            sent, requested,
+            "UDPBD_CMD_READ sent a different size than requested"
+        );
+        if self.verify_reads && sent != requested {
+            error!(
+                "UDPBD_CMD_READ for {addr} sent {sent} bytes but {requested} were requested (block shift {block_shift})"
+            );
+        }
+    }
+
+    /// Sends a batch of RDMA packets of a read, or hands it to a send worker, leaving it empty.
+    fn send_read_batch(&mut self, batch: &mut PacketBatch, addr: SocketAddr) {
+        if batch.is_empty() {
+            return;
+        }
+
+        if let Some(send_pool) = &self.send_pool {
+            // failures are only known to the worker, count the packets as sent once queued
+            self.stats.packets_sent += batch.len() as u64;
+            let spare = spare_batch(&mut self.spare_batches, Some(send_pool));
+            send_pool.send(std::mem::replace(batch, spare), addr);
+            return;
+        }
 
-            let ser = bytemuck::bytes_of(&reply);
-            let resp = &ser[..size_of::<Header>() + size_of::<BlockType>() + size];
+        match send_packets(&self.socket, batch, addr) {
+            Ok(()) => self.stats.packets_sent += batch.len() as u64,
+            Err(err) => warn!("Failed to reply with UDPBD_CMD_READ_RDMA to {addr}: {err}"),
+        }
+        batch.clear();
+    }
 
-            // send packet to PS2
-            if let Err(err) = self.socket.send_to(resp, addr) {
-                eprintln!("Failed to reply with UDPBD_CMD_READ_RDMA to {addr}: {err}");
+    /// Looks into the chunk at `offset` that failed to read with `err`. If the mapped file there
+    /// explains the failure, e.g. it was deleted or truncated while serving, names it once and
+    /// returns true. Later reads of the file get zeros without another report.
+    fn report_unreadable_file(&mut self, offset: u64, size: usize, err: &io::Error) -> bool {
+        match self.unreadable_file(offset, err) {
+            Some((path, problem)) => {
+                if self.unreadable_files.insert(path.clone()) {
+                    error!(
+                        event = "unreadable_file", path = path.as_str();
+                        "Cannot read mapped file {path}: {problem}, sending zeros in its place"
+                    );
+                } else {
+                    debug!("Sending zeros for unreadable mapped file {path}");
+                }
+                true
             }
+            None if err.kind() == io::ErrorKind::PermissionDenied => {
+                let sector_size = u64::from(self.block_device.sector_size());
+                let sectors = offset / sector_size..(offset + size as u64).div_ceil(sector_size);
+                if self.unreadable_sectors.insert(sectors.clone()) {
+                    error!(
+                        "Failed to read block device at sectors {}-{}: permission denied, sending zeros",
+                        sectors.start,
+                        sectors.end - 1
+                    );
+                } else {
+                    debug!("Sending zeros for unreadable sector {}", sectors.start);
+                }
+                true
+            }
+            None => false,
+        }
+    }
 
-            let next_cmd_pkt = reply.header.command_pkt() + 1;
-            reply.header = reply.header.with_command_pkt(next_cmd_pkt);
+    /// Path on the volume of the mapped file at `offset` and what is wrong with it, if its
+    /// host file explains `err`. The host file is checked again to tell a file that was
+    /// deleted or shrunk apart from storage failing as a whole.
+    fn unreadable_file(&self, offset: u64, err: &io::Error) -> Option<(String, String)> {
+        let path = self.block_device.file_at(offset)?.path.clone();
+        if err.kind() == io::ErrorKind::PermissionDenied {
+            return Some((path, "permission denied; check file permissions".into()));
+        }
+        if self.unreadable_files.contains(&path) {
+            // the rest of a file already found broken, no need to look at it again
+            return Some((path, "already reported".into()));
         }
+
+        let mapped_size = self.block_device.mapped_size(&path);
+        let host_path = self.block_device.host_path(&path)?;
+        let problem = match std::fs::metadata(host_path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                format!("{} was deleted or moved while serving", host_path.display())
+            }
+            Ok(metadata) if metadata.len() < mapped_size => format!(
+                "{} shrank from {mapped_size} to {} bytes while serving",
+                host_path.display(),
+                metadata.len()
+            ),
+            _ => return None,
+        };
+
+        Some((path, problem))
     }
 
-    fn handle_cmd_write(&mut self, req: &ReadWriteRequest) {
+    fn handle_cmd_write(&mut self, req: &ReadWriteRequest, addr: SocketAddr) {
         let ReadWriteRequest {
             sector_nr,
             sector_count,
             ..
         } = *req;
-        println!(
+        debug!(
+            event = "write", client:% = addr, sector = sector_nr, count = sector_count;
             "UDPBD_CMD_WRITE(cmdId={}, startSector={}, sectorCount={})",
             req.header.command_id(),
             sector_nr,
             sector_count
         );
 
-        self.write_size_left =
-            usize::from(sector_count) * usize::from(self.block_device.sector_size());
+        self.stats.write_requests += 1;
+        self.stats.client_seen(addr.ip());
+        if sector_count == 0 {
+            // no RDMA packets follow, the write is already complete
+            *client_write(&mut self.writes, addr) = WriteSession::default();
+            self.send_write_done(write_done_header(req.header), WRITE_RESULT_OK, addr);
+            return;
+        }
 
-        match self.block_device.seek(sector_nr) {
-            Ok(_) => {
-                self.write_rdma_valid = true;
-            }
-            Err(err) => {
-                eprintln!("Failed to seek to sector {sector_nr}: {err}");
-                self.write_rdma_valid = false;
-            }
+        let writable = match &mut self.free_space {
+            Some(free_space) => free_space.allows_writes(),
+            None => true,
+        };
+
+        let sector_size = self.block_device.sector_size();
+        let write = client_write(&mut self.writes, addr);
+        if write.size_left > 0 {
+            warn!(
+                "Abandoning the unfinished write from {addr} with {} bytes outstanding",
+                write.size_left
+            );
         }
+
+        // start over, nothing of the previous write carries into this one
+        *write = WriteSession {
+            offset: u64::from(sector_nr) * u64::from(sector_size),
+            size_left: usize::from(sector_count) * usize::from(sector_size),
+            rdma_valid: writable,
+            result: if writable {
+                WRITE_RESULT_OK
+            } else {
+                WRITE_RESULT_ERROR
+            },
+            next_pkt: 1,
+        };
     }
 
     fn handle_cmd_write_rdma(&mut self, req: &Rdma, addr: SocketAddr) {
         let size = req.block_type.blocks_size();
         let data = &req.data[..size];
 
-        #[allow(clippy::collapsible_if)]
-        if self.write_rdma_valid {
-            if self.block_device.write(data).is_err() {
-                eprintln!("Failed to write data to block device");
-            }
+        let write = client_write(&mut self.writes, addr);
+        if write.size_left == 0 {
+            debug!("Dropping UDPBD_CMD_WRITE_RDMA from {addr}, no write in progress");
+            return;
         }
 
-        match self.write_size_left.checked_sub(size) {
-            Some(new_size) => self.write_size_left = new_size,
-            None => {
-                eprintln!("write_size_left wraparound at 0");
-                self.write_size_left = 0;
+        let pkt = req.header.command_pkt();
+        let expected = write.next_pkt;
+        if write.rdma_valid && !write.in_sequence(pkt) {
+            error!(
+                "Failing the write from {addr}, expected RDMA packet {expected} but got {pkt}, packets were lost or reordered"
+            );
+        }
+        if write.rdma_valid && size > write.size_left {
+            // writing it would spill past the sectors the client asked for
+            error!(
+                "Failing the write from {addr}, RDMA packet {pkt} carries {size} bytes but only {} are left",
+                write.size_left
+            );
+            write.rdma_valid = false;
+            write.result = WRITE_RESULT_ERROR;
+        }
+        if write.rdma_valid {
+            // another client may have moved the position since the last packet
+            let written = self
+                .block_device
+                .seek_offset(write.offset)
+                .and_then(|_| self.block_device.write(data));
+            if let Err(err) = written {
+                error!(event = "write_error"; "Failed to write data to block device: {err}");
+                write.result = WRITE_RESULT_ERROR;
             }
         }
+        write.offset += size as u64;
+        self.stats.bytes_written += size as u64;
+        self.stats.transferred(size as u64);
 
-        if self.write_size_left == 0 {
-            let reply = WriteReply {
-                header: Header::new_with_raw_value(0)
-                    .with_command(Command::WriteDone)
-                    .with_command_id(req.header.command_id())
-                    .with_command_pkt(req.header.command_id().value() + 1), // ?
-                result: 0,
-            };
-            let ser = bytemuck::bytes_of(&reply);
+        if write.consume(size) {
+            let result = write.result;
+            self.send_write_done(write_done_header(req.header), result, addr);
+        }
+    }
 
-            if let Err(err) = self.socket.send_to(ser, addr) {
-                eprintln!("Failed to reply with UDPBD_CMD_WRITE_DONE to {addr}: {err}");
-            };
+    fn send_write_done(&mut self, header: Header, result: i32, addr: SocketAddr) {
+        let reply = WriteReply { header, result };
+        let ser = bytemuck::bytes_of(&reply);
+
+        match self.socket.send_to(ser, addr) {
+            Ok(_) => self.stats.packets_sent += 1,
+            Err(err) => warn!("Failed to reply with UDPBD_CMD_WRITE_DONE to {addr}: {err}"),
+        }
+    }
+}
+
+#[test]
+fn write_size_left_underflow() {
+    let mut write = WriteSession {
+        size_left: 512,
+        rdma_valid: true,
+        ..Default::default()
+    };
+
+    // more data than the write announced completes it instead of wrapping around
+    assert!(write.consume(1408));
+    assert_eq!(write.size_left, 0);
+
+    // the next write sequence is unaffected
+    write.size_left = 1024;
+    assert!(!write.consume(512));
+    assert!(write.consume(512));
+}
+
+#[test]
+fn write_done_follows_last_rdma_packet() {
+    let last_rdma = Header::new_with_raw_value(0)
+        .with_command(Command::WriteRdma)
+        .with_command_id(u3::new(5))
+        .with_command_pkt(3);
+
+    let header = write_done_header(last_rdma);
+    assert!(matches!(header.command(), Ok(Command::WriteDone)));
+    assert_eq!(header.command_id(), u3::new(5));
+    assert_eq!(header.command_pkt(), 4);
+}
+
+#[test]
+fn write_fails_on_missing_packet() {
+    let mut write = WriteSession {
+        size_left: 3 * 512,
+        rdma_valid: true,
+        next_pkt: 1,
+        ..Default::default()
+    };
+
+    assert!(write.in_sequence(1));
+    assert!(!write.in_sequence(3));
+    assert!(!write.rdma_valid);
+    assert_eq!(write.result, WRITE_RESULT_ERROR);
+}
+
+#[test]
+fn zero_sector_requests() {
+    let root = crate::utils::test_dir("zero-sector-requests");
+    let mut server = test_server(&root, |_| {});
+
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let addr = client.local_addr().unwrap();
+    let request = |command| ReadWriteRequest {
+        header: Header::new_with_raw_value(0).with_command(command),
+        sector_nr: 0,
+        sector_count: 0,
+    };
+
+    // a read of nothing gets no RDMA packets and doesn't pick a block size
+    server.handle_packet(bytemuck::bytes_of(&request(Command::Read)), addr, false);
+    assert!(client.recv(&mut [0; 16]).is_err());
+    assert!(!server.reads.contains_key(&addr));
+
+    // a write of nothing completes right away
+    server.handle_packet(bytemuck::bytes_of(&request(Command::Write)), addr, false);
+    let mut buf = [0; size_of::<WriteReply>()];
+    assert_eq!(client.recv(&mut buf).unwrap(), buf.len());
+    let reply: WriteReply = bytemuck::pod_read_unaligned(&buf);
+    assert!(matches!(reply.header.command(), Ok(Command::WriteDone)));
+    assert_eq!({ reply.result }, WRITE_RESULT_OK);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn storage_unavailable_after_consecutive_failures() {
+    let mut storage = StorageHealth::new(3, Duration::from_secs(60));
+
+    // isolated failures are logged and don't stop serving
+    assert!(storage.failed());
+    storage.succeeded();
+    assert!(storage.failed());
+    assert!(storage.failed());
+    assert!(storage.should_serve());
+
+    // the failure crossing the threshold is the last one logged
+    assert!(storage.failed());
+    assert!(!storage.should_serve());
+    assert!(!storage.failed());
+
+    storage.succeeded();
+    assert!(storage.should_serve());
+    assert!(storage.failed());
+}
+
+#[test]
+fn unreadable_sectors_are_reported_once() {
+    let mut ranges = SectorRanges::default();
+
+    assert!(ranges.insert(10..12));
+    // a read continuing into the region or overlapping it is the same problem
+    assert!(!ranges.insert(12..14));
+    assert!(!ranges.insert(8..11));
+    assert!(!ranges.insert(13..20));
+    assert_eq!(ranges.0, BTreeMap::from([(8, 20)]));
+
+    assert!(ranges.insert(30..31));
+    // filling the gap merges both regions
+    assert!(!ranges.insert(20..30));
+    assert_eq!(ranges.0, BTreeMap::from([(8, 31)]));
+
+    ranges.clear();
+    assert!(ranges.insert(10..12));
+}
+
+#[test]
+fn client_writes_are_separate() {
+    let a = SocketAddr::from(([192, 168, 0, 10], 0xBDBD));
+    let b = SocketAddr::from(([192, 168, 0, 11], 0xBDBD));
+    let mut writes = HashMap::new();
+
+    client_write(&mut writes, a).size_left = 1024;
+    client_write(&mut writes, b).size_left = 512;
+    assert!(client_write(&mut writes, b).consume(512));
+    assert_eq!(client_write(&mut writes, a).size_left, 1024);
+
+    // a half-finished write of a client returning after going idle is discarded
+    client_write(&mut writes, b).size_left = 512;
+    writes.get_mut(&b).unwrap().last_seen -= CLIENT_IDLE_TIMEOUT;
+    assert_eq!(client_write(&mut writes, b).size_left, 0);
+
+    // idle clients are evicted when another client writes
+    writes.get_mut(&a).unwrap().last_seen -= CLIENT_IDLE_TIMEOUT;
+    client_write(&mut writes, b);
+    assert!(!writes.contains_key(&a));
+}
+
+#[test]
+fn client_block_sizes_are_separate() {
+    let a = SocketAddr::from(([192, 168, 0, 10], 0xBDBD));
+    let b = SocketAddr::from(([192, 168, 0, 11], 0xBDBD));
+    let mut initial = BlockGeometry::new(512);
+    initial.set_block_shift(DEFAULT_BLOCK_SHIFT);
+    let mut reads = HashMap::new();
+
+    // one client reading single sectors doesn't change the blocks of another streaming
+    client_read(&mut reads, a, &initial).set_block_shift_sectors(1);
+    client_read(&mut reads, b, &initial).set_block_shift_sectors(64);
+    assert_eq!(client_read(&mut reads, a, &initial).block_size, 512);
+    assert_ne!(client_read(&mut reads, b, &initial).block_size, 512);
+
+    // idle clients are evicted when another client reads
+    reads.get_mut(&a).unwrap().last_seen -= CLIENT_IDLE_TIMEOUT;
+    client_read(&mut reads, b, &initial);
+    assert!(!reads.contains_key(&a));
+}
+
+#[test]
+fn packet_split() {
+    let counts: Vec<u16> = packet_block_counts(25, 11).unwrap().collect();
+    assert_eq!(counts, [11, 11, 3]);
+
+    // the largest read doesn't overflow the block count
+    let counts = packet_block_counts(u32::from(u16::MAX) * 128, 2).unwrap();
+    assert_eq!(counts.count(), usize::from(u16::MAX) * 64);
+
+    // a block size too big for a packet must not loop forever
+    assert!(packet_block_counts(16, 0).is_none());
+}
+
+#[test]
+fn throughput_covers_last_second() {
+    let mut stats = Stats::default();
+    stats.transferred(1024);
+    // still counting the current second
+    assert_eq!(stats.throughput(), 0);
+
+    stats.window_started -= Duration::from_millis(1500);
+    assert_eq!(stats.throughput(), 1024);
+    stats.transferred(512);
+    assert_eq!(stats.throughput(), 1024);
+
+    stats.window_started -= Duration::from_secs(5);
+    assert_eq!(stats.throughput(), 0);
+}
+
+#[test]
+fn beacon_waits_for_interval() {
+    let mut beacon = Beacon {
+        interval: Duration::from_secs(5),
+        sent_at: None,
+    };
+
+    // the first beacon goes out right away
+    assert!(beacon.due());
+    assert!(!beacon.due());
+
+    beacon.sent_at = beacon.sent_at.map(|at| at - Duration::from_secs(5));
+    assert!(beacon.due());
+}
+
+#[test]
+fn cidr_ranges() {
+    let ip = |ip: [u8; 4]| IpAddr::from(ip);
+
+    let lan: Ipv4Net = "192.168.1.0/24".parse().unwrap();
+    assert!(lan.contains(ip([192, 168, 1, 42])));
+    assert!(!lan.contains(ip([192, 168, 2, 42])));
+
+    let host: Ipv4Net = "10.0.0.5".parse().unwrap();
+    assert!(host.contains(ip([10, 0, 0, 5])));
+    assert!(!host.contains(ip([10, 0, 0, 6])));
+
+    let any: Ipv4Net = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains(ip([8, 8, 8, 8])));
+
+    assert!("192.168.1.0/33".parse::<Ipv4Net>().is_err());
+    assert!("192.168.1/24".parse::<Ipv4Net>().is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn subnet_broadcasts_follow_the_interface() {
+    let mut interfaces = InterfaceBroadcasts {
+        addrs: HashMap::from([(2, vec![Ipv4Addr::new(192, 168, 1, 255)])]),
+        read_at: Some(Instant::now()),
+    };
+
+    assert!(interfaces.is_broadcast(Ipv4Addr::BROADCAST, 2));
+    assert!(interfaces.is_broadcast(Ipv4Addr::new(192, 168, 1, 255), 2));
+    // a host address ending in .255 on a wider subnet isn't a broadcast
+    assert!(!interfaces.is_broadcast(Ipv4Addr::new(10, 0, 0, 255), 2));
+    assert!(!interfaces.is_broadcast(Ipv4Addr::new(192, 168, 1, 10), 2));
+}
+
+#[test]
+fn send_workers_keep_client_packets_in_order() {
+    let root = crate::utils::test_dir("send-workers");
+    std::fs::write(root.join("file.bin"), vec![0xAB; 64 * 1024]).unwrap();
+    let mut server = test_server(&root, |config| config.send_workers = 2);
+
+    let clients: Vec<_> = (0..2)
+        .map(|_| {
+            let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+            client
+                .set_read_timeout(Some(Duration::from_millis(500)))
+                .unwrap();
+            client
+        })
+        .collect();
+    let sector_count = 128;
+    let request = ReadWriteRequest {
+        header: Header::new_with_raw_value(0).with_command(Command::Read),
+        sector_nr: 0,
+        sector_count,
+    };
+
+    for client in &clients {
+        let addr = client.local_addr().unwrap();
+        server.handle_packet(bytemuck::bytes_of(&request), addr, false);
+    }
+    server.send_pool.take().unwrap().finish();
+
+    for client in &clients {
+        let mut buf = [0; UDP_MAX_PAYLOAD];
+        let mut received = 0;
+        let mut expected_pkt = 1;
+        while let Ok(len) = client.recv(&mut buf) {
+            let mut reply = Rdma::zeroed();
+            bytemuck::bytes_of_mut(&mut reply)[..len].copy_from_slice(&buf[..len]);
+            assert_eq!(reply.header.command_pkt(), expected_pkt);
+            expected_pkt = expected_pkt.wrapping_add(1);
+            received += reply.block_type.blocks_size();
+        }
+        assert_eq!(
+            received,
+            usize::from(sector_count) * usize::from(server.block_device.sector_size())
+        );
+    }
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn read_batches_are_reused() {
+    let root = crate::utils::test_dir("read-batches");
+    std::fs::write(root.join("file.bin"), vec![0xAB; 64 * 1024]).unwrap();
+    let mut server = test_server(&root, |_| {});
+
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = client.local_addr().unwrap();
+    let request = ReadWriteRequest {
+        header: Header::new_with_raw_value(0).with_command(Command::Read),
+        sector_nr: 0,
+        sector_count: 128,
+    };
+
+    // reads of several batches each send them all from the one batch kept between reads
+    for _ in 0..2 {
+        server.handle_packet(bytemuck::bytes_of(&request), addr, false);
+        assert_eq!(server.spare_batches.len(), 1);
+        assert!(server.spare_batches[0].is_empty());
+    }
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+/// Config for a server of `root` on a loopback port, adjusted by `configure`.
+#[cfg(test)]
+fn test_config(root: &std::path::Path, configure: impl FnOnce(&mut ServerConfig)) -> ServerConfig {
+    let mut config = ServerConfig::new(VexFatConfig::new(root));
+    config.bind = Ipv4Addr::LOCALHOST;
+    config.port = 0;
+    configure(&mut config);
+
+    config
+}
+
+/// Server for `root` on a loopback port, adjusted by `configure`, for driving by hand.
+#[cfg(test)]
+fn test_server(root: &std::path::Path, configure: impl FnOnce(&mut ServerConfig)) -> Server {
+    Server::new(&test_config(root, configure)).unwrap()
+}
+
+/// Runs a server for `root` on a loopback port on its own thread, returning its address and
+/// the flag that stops it.
+#[cfg(test)]
+fn spawn_test_server(
+    root: &std::path::Path,
+) -> (SocketAddr, Arc<AtomicBool>, thread::JoinHandle<()>) {
+    spawn_test_server_with(root, |_| {})
+}
+
+/// Like [`spawn_test_server`], with the config adjusted by `configure` first.
+#[cfg(test)]
+fn spawn_test_server_with(
+    root: &std::path::Path,
+    configure: impl FnOnce(&mut ServerConfig),
+) -> (SocketAddr, Arc<AtomicBool>, thread::JoinHandle<()>) {
+    let config = test_config(root, configure);
+
+    let (started, server_info) = mpsc::channel();
+    let thread = thread::spawn(move || {
+        let mut server = Server::new(&config).unwrap();
+        let addr = server.socket.local_addr().unwrap();
+        started.send((addr, server.shutdown_handle())).unwrap();
+        server.run().unwrap();
+    });
+    let (addr, shutdown) = server_info.recv().unwrap();
+
+    (addr, shutdown, thread)
+}
+
+#[cfg(test)]
+fn test_client() -> UdpSocket {
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_secs(2)))
+        .unwrap();
+
+    client
+}
+
+/// Reads `sectors` sectors from `sector_nr` on the server at `addr` the way OPL streams a game,
+/// a request at a time, asking again for a request whose packets got lost. Returns the data read.
+#[cfg(test)]
+fn stream_sectors(addr: SocketAddr, sector_nr: u32, sectors: u32) -> Vec<u8> {
+    const REQUEST_SECTORS: u32 = 128;
+
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let mut buf = [0; UDP_MAX_PAYLOAD];
+    let mut data = Vec::with_capacity(sectors as usize * 512);
+    for first in (sector_nr..sector_nr + sectors).step_by(REQUEST_SECTORS as usize) {
+        let count = REQUEST_SECTORS.min(sector_nr + sectors - first);
+        let request = ReadWriteRequest {
+            header: Header::new_with_raw_value(0).with_command(Command::Read),
+            sector_nr: first,
+            sector_count: count as u16,
+        };
+        loop {
+            client.send_to(bytemuck::bytes_of(&request), addr).unwrap();
+            let mut received = Vec::with_capacity(count as usize * 512);
+            while received.len() < count as usize * 512 {
+                let Ok(len) = client.recv(&mut buf) else {
+                    break;
+                };
+                let mut reply = Rdma::zeroed();
+                bytemuck::bytes_of_mut(&mut reply)[..len].copy_from_slice(&buf[..len]);
+                received.extend_from_slice(&reply.data[..reply.block_type.blocks_size()]);
+            }
+            if received.len() == count as usize * 512 {
+                data.extend_from_slice(&received);
+                break;
+            }
         }
     }
+
+    data
+}
+
+#[test]
+fn two_clients_stream_with_send_workers() {
+    const FILE_SIZE: usize = 2 * 1024 * 1024;
+
+    let root = crate::utils::test_dir("two-clients-stream");
+    let files = [("a.bin", 0x5A), ("b.bin", 0xA5)];
+    for (name, byte) in files {
+        std::fs::write(root.join(name), vec![byte; FILE_SIZE]).unwrap();
+    }
+    let mut vexfat = crate::vexfat::test_vexfat(&root, |_| {});
+    let sector_size = u64::from(vexfat.sector_size());
+    let extents = crate::exfat::file_extents(&mut vexfat).unwrap();
+    let (addr, shutdown, server) = spawn_test_server_with(&root, |config| config.send_workers = 2);
+
+    // both read at once, each must get its own file whole and in order
+    let clients: Vec<_> = files
+        .into_iter()
+        .map(|(name, byte)| {
+            let extent = extents
+                .iter()
+                .find(|extent| extent.path.ends_with(name))
+                .unwrap();
+            let sector_nr = (extent.volume_offset / sector_size) as u32;
+            let sectors = (FILE_SIZE as u64 / sector_size) as u32;
+            (
+                byte,
+                thread::spawn(move || stream_sectors(addr, sector_nr, sectors)),
+            )
+        })
+        .collect();
+    for (byte, client) in clients {
+        let data = client.join().unwrap();
+        assert_eq!(data.len(), FILE_SIZE);
+        assert!(data.iter().all(|&read| read == byte));
+    }
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn info_over_udp() {
+    let root = crate::utils::test_dir("info-over-udp");
+    std::fs::write(root.join("file.bin"), [0xAA; 4096]).unwrap();
+    let vexfat = crate::vexfat::test_vexfat(&root, |_| {});
+    let (addr, shutdown, server) = spawn_test_server(&root);
+
+    let client = test_client();
+    let request = InfoRequest {
+        header: Header::new_with_raw_value(0)
+            .with_command(Command::Info)
+            .with_command_id(u3::new(3)),
+    };
+    client.send_to(bytemuck::bytes_of(&request), addr).unwrap();
+
+    let mut buf = [0; size_of::<InfoReply>()];
+    assert_eq!(client.recv(&mut buf).unwrap(), buf.len());
+    let reply: InfoReply = bytemuck::pod_read_unaligned(&buf);
+    assert!(matches!(reply.header.command(), Ok(Command::InfoReply)));
+    assert_eq!(reply.header.command_id(), u3::new(3));
+    assert_eq!({ reply.sector_size }, u32::from(vexfat.sector_size()));
+    assert_eq!({ reply.sector_count }, vexfat.sector_count());
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn read_over_udp() {
+    let root = crate::utils::test_dir("read-over-udp");
+    let data: Vec<u8> = (0..16 * 1024).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root.join("file.bin"), &data).unwrap();
+
+    // the server maps root the same way, so the file lands at the same place
+    let mut vexfat = crate::vexfat::test_vexfat(&root, |_| {});
+    let sector_size = u64::from(vexfat.sector_size());
+    let extent = crate::exfat::file_extents(&mut vexfat)
+        .unwrap()
+        .into_iter()
+        .find(|extent| extent.path.ends_with("file.bin"))
+        .unwrap();
+    let (addr, shutdown, server) = spawn_test_server(&root);
+
+    let client = test_client();
+    let request = ReadWriteRequest {
+        header: Header::new_with_raw_value(0).with_command(Command::Read),
+        sector_nr: (extent.volume_offset / sector_size) as u32,
+        sector_count: (data.len() as u64 / sector_size) as u16,
+    };
+    client.send_to(bytemuck::bytes_of(&request), addr).unwrap();
+
+    let mut received = Vec::new();
+    let mut buf = [0; UDP_MAX_PAYLOAD];
+    while received.len() < data.len() {
+        let len = client.recv(&mut buf).unwrap();
+        let mut reply = Rdma::zeroed();
+        bytemuck::bytes_of_mut(&mut reply)[..len].copy_from_slice(&buf[..len]);
+        assert!(matches!(reply.header.command(), Ok(Command::ReadRdma)));
+        received.extend_from_slice(&reply.data[..reply.block_type.blocks_size()]);
+    }
+    assert!(received == data);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn oversized_block_type_is_dropped() {
+    let root = crate::utils::test_dir("oversized-block-type");
+    let mut server = test_server(&root, |_| {});
+
+    // the largest block shift and count describe far more than any packet can carry
+    let header = Header::new_with_raw_value(0).with_command(Command::WriteRdma);
+    let block_type = BlockType::new_with_raw_value(0)
+        .with_block_shift(u4::new(15))
+        .with_block_count(u9::new(511));
+    let mut packet = bytemuck::bytes_of(&header).to_vec();
+    packet.extend_from_slice(bytemuck::bytes_of(&block_type));
+    packet.resize(UDP_MAX_PAYLOAD, 0);
+
+    // in the middle of a one sector write
+    let client = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    client
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let addr = client.local_addr().unwrap();
+    let write = ReadWriteRequest {
+        header: Header::new_with_raw_value(0).with_command(Command::Write),
+        sector_nr: 0,
+        sector_count: 1,
+    };
+    server.handle_packet(bytemuck::bytes_of(&write), addr, false);
+
+    server.handle_packet(&packet, addr, false);
+
+    // dropped without a reply, the write goes on as if the packet never arrived
+    let mut buf = [0; UDP_MAX_PAYLOAD];
+    assert!(client.recv(&mut buf).is_err());
+    let session = client_write(&mut server.writes, addr);
+    assert_eq!(session.size_left, 512);
+    assert!(session.rdma_valid);
+    assert_eq!(session.next_pkt, 1);
+    assert_eq!(server.stats.bytes_written, 0);
+    assert_eq!(server.stats.packets_sent, 0);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn vanished_and_truncated_files() {
+    let root = crate::utils::test_dir("vanished-files");
+    std::fs::write(root.join("gone.bin"), [1; 4096]).unwrap();
+    std::fs::write(root.join("short.bin"), [2; 4096]).unwrap();
+    let mut server = test_server(&root, |_| {});
+
+    let extents = crate::exfat::file_extents(&mut server.block_device).unwrap();
+    let offset_of = |name: &str| {
+        extents
+            .iter()
+            .find(|extent| extent.path.ends_with(name))
+            .unwrap()
+            .volume_offset
+    };
+    let err = io::Error::other("read failed");
+
+    // a file that is still intact doesn't explain the failure
+    assert!(server
+        .unreadable_file(offset_of("gone.bin"), &err)
+        .is_none());
+
+    std::fs::remove_file(root.join("gone.bin")).unwrap();
+    let (path, problem) = server.unreadable_file(offset_of("gone.bin"), &err).unwrap();
+    assert!(path.ends_with("gone.bin"));
+    assert!(problem.contains("deleted"));
+
+    std::fs::write(root.join("short.bin"), [2; 100]).unwrap();
+    let (path, problem) = server
+        .unreadable_file(offset_of("short.bin"), &err)
+        .unwrap();
+    assert!(path.ends_with("short.bin"));
+    assert!(problem.contains("shrank from 4096 to 100 bytes"));
+
+    // reported once, without counting against the storage
+    assert!(server.report_unreadable_file(offset_of("short.bin"), 512, &err));
+    assert!(server.unreadable_files.contains(&path));
+    assert_eq!(server.storage.consecutive_failures, 0);
+
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn oversized_write_over_udp() {
+    let root = crate::utils::test_dir("oversized-write-over-udp");
+    std::fs::write(root.join("file.bin"), [0x11; 4096]).unwrap();
+    let mut vexfat = crate::vexfat::test_vexfat(&root, |_| {});
+    let sector_size = u64::from(vexfat.sector_size());
+    let extent = crate::exfat::file_extents(&mut vexfat)
+        .unwrap()
+        .into_iter()
+        .find(|extent| extent.path.ends_with("file.bin"))
+        .unwrap();
+    let (addr, shutdown, server) = spawn_test_server(&root);
+    let client = test_client();
+
+    let write = ReadWriteRequest {
+        header: Header::new_with_raw_value(0)
+            .with_command(Command::Write)
+            .with_command_id(u3::new(1)),
+        sector_nr: (extent.volume_offset / sector_size) as u32,
+        sector_count: 1,
+    };
+    client.send_to(bytemuck::bytes_of(&write), addr).unwrap();
+
+    // two 512 byte blocks for a write of one sector
+    let mut rdma = Rdma::zeroed();
+    rdma.header = Header::new_with_raw_value(0)
+        .with_command(Command::WriteRdma)
+        .with_command_id(u3::new(1))
+        .with_command_pkt(1);
+    rdma.block_type = BlockType::new_with_raw_value(0)
+        .with_block_shift(u4::new(7))
+        .with_block_count(u9::new(2));
+    rdma.data[..1024].fill(0x22);
+    let len = size_of::<Header>() + size_of::<BlockType>() + 1024;
+    client
+        .send_to(&bytemuck::bytes_of(&rdma)[..len], addr)
+        .unwrap();
+
+    let mut buf = [0; size_of::<WriteReply>()];
+    assert_eq!(client.recv(&mut buf).unwrap(), buf.len());
+    let reply: WriteReply = bytemuck::pod_read_unaligned(&buf);
+    assert!(matches!(reply.header.command(), Ok(Command::WriteDone)));
+    assert_eq!(reply.header.command_id(), u3::new(1));
+    assert_eq!({ reply.result }, WRITE_RESULT_ERROR);
+    assert_eq!(std::fs::read(root.join("file.bin")).unwrap(), [0x11; 4096]);
+
+    // the server is still answering
+    let info = InfoRequest {
+        header: Header::new_with_raw_value(0).with_command(Command::Info),
+    };
+    client.send_to(bytemuck::bytes_of(&info), addr).unwrap();
+    let mut buf = [0; size_of::<InfoReply>()];
+    assert_eq!(client.recv(&mut buf).unwrap(), buf.len());
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+    std::fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn unknown_command_fails_fast() {
+    let root = crate::utils::test_dir("unknown-command");
+    let (addr, shutdown, server) = spawn_test_server(&root);
+    let client = test_client();
+
+    // a reply meant for clients is ignored, the first reply is to the unknown command
+    let reply_header = Header::new_with_raw_value(0).with_command(Command::WriteDone);
+    client
+        .send_to(bytemuck::bytes_of(&reply_header), addr)
+        .unwrap();
+    let unknown = Header::new_with_raw_value(0x1F).with_command_id(u3::new(2));
+    client.send_to(bytemuck::bytes_of(&unknown), addr).unwrap();
+
+    let mut buf = [0; size_of::<WriteReply>()];
+    assert_eq!(client.recv(&mut buf).unwrap(), buf.len());
+    let reply: WriteReply = bytemuck::pod_read_unaligned(&buf);
+    assert!(matches!(reply.header.command(), Ok(Command::WriteDone)));
+    assert_eq!(reply.header.command_id(), u3::new(2));
+    assert_eq!({ reply.result }, RESULT_UNSUPPORTED);
+
+    shutdown.store(true, Ordering::SeqCst);
+    server.join().unwrap();
+    std::fs::remove_dir_all(root).unwrap();
 }
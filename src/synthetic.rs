@@ -0,0 +1,48 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use log::{debug, info};
+
+const FILE_COUNT: u64 = 4;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Generates a few files filled with a known pattern into a temporary directory to serve
+/// instead of a real OPL root. Every little-endian u32 word of a file holds its own index
+/// within the file, so a client can check any range it reads.
+pub fn generate(size: u64) -> io::Result<PathBuf> {
+    let root = std::env::temp_dir().join(format!("udpbd-vexfat-synthetic-{}", std::process::id()));
+    if root.exists() {
+        fs::remove_dir_all(&root)?;
+    }
+
+    let dir = root.join("SYNTHETIC");
+    fs::create_dir_all(&dir)?;
+
+    let file_size = (size / FILE_COUNT / 512).max(1) * 512;
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+
+    info!("Generating synthetic volume in {}", root.display());
+    for index in 0..FILE_COUNT {
+        let name = format!("pattern{index}.bin");
+        let mut file = File::create(dir.join(&name))?;
+
+        let mut offset = 0;
+        while offset < file_size {
+            let len = CHUNK_SIZE.min((file_size - offset) as usize);
+            let first_word = (offset / 4) as u32;
+            for (i, word) in chunk[..len].chunks_exact_mut(4).enumerate() {
+                word.copy_from_slice(&first_word.wrapping_add(i as u32).to_le_bytes());
+            }
+
+            file.write_all(&chunk[..len])?;
+            offset += len as u64;
+        }
+
+        debug!(" - SYNTHETIC/{name}, {file_size} bytes");
+    }
+
+    Ok(root)
+}
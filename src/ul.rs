@@ -0,0 +1,200 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use log::warn;
+
+/// Size of a game entry in ul.cfg.
+const ENTRY_SIZE: usize = 64;
+
+/// Longest game name an entry holds, it isn't NUL terminated when it takes all of it.
+const MAX_NAME_LEN: usize = 32;
+
+/// Media byte of an entry.
+const MEDIA_CD: u8 = 0x12;
+const MEDIA_DVD: u8 = 0x14;
+
+/// Game split into `ul.CRC.SERIAL.NN` parts by USBUtil and friends.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SplitGame {
+    /// CRC of the game name the parts were named after.
+    pub crc: u32,
+    /// Serial of the boot executable, e.g. `SLUS_203.12`.
+    pub serial: String,
+    /// Host paths of the parts, in order.
+    pub parts: Vec<PathBuf>,
+}
+
+impl SplitGame {
+    /// Name part `index` is looked up by once the game is called `name` in ul.cfg.
+    pub fn part_name(&self, name: &str, index: usize) -> String {
+        format!("ul.{:08X}.{}.{index:02x}", crc32(name), self.serial)
+    }
+}
+
+/// CRC OPL names the parts of a game after, computed over the name and its terminating NUL.
+/// Follows USBA_crc32 in OPL, seed included.
+pub fn crc32(name: &str) -> u32 {
+    let mut table = [0u32; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = ((255 - i) as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                crc << 1
+            } else {
+                (crc << 1) ^ 0x04C1_1DB7
+            };
+        }
+        *entry = crc;
+    }
+
+    // OPL starts from the entry its table loop happened to compute last
+    let mut crc = table[0];
+    for byte in name.bytes().chain([0]) {
+        crc = table[usize::from(byte ^ (crc >> 24) as u8)] ^ (crc << 8);
+    }
+
+    crc
+}
+
+/// CRC, serial and part index of a `ul.CRC.SERIAL.NN` part name.
+fn parse_part_name(name: &str) -> Option<(u32, &str, usize)> {
+    let rest = name.strip_prefix("ul.")?;
+    let (crc, rest) = rest.split_once('.')?;
+    let (serial, part) = rest.rsplit_once('.')?;
+    if crc.len() != 8 || part.len() != 2 || serial.is_empty() {
+        return None;
+    }
+
+    let crc = u32::from_str_radix(crc, 16).ok()?;
+    let part = usize::from_str_radix(part, 16).ok()?;
+    Some((crc, serial, part))
+}
+
+/// Groups the parts among `paths` into games. Sets missing a part are reported and left out,
+/// OPL would fail to boot them.
+pub fn find_split_games<'a>(paths: impl IntoIterator<Item = &'a Path>) -> Vec<SplitGame> {
+    let mut sets = BTreeMap::<(u32, String), BTreeMap<usize, PathBuf>>::new();
+    for path in paths {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some((crc, serial, part)) = parse_part_name(&name) {
+            sets.entry((crc, serial.to_owned()))
+                .or_default()
+                .insert(part, path.to_owned());
+        }
+    }
+
+    let mut games = Vec::new();
+    for ((crc, serial), parts) in sets {
+        let count = parts.keys().last().map_or(0, |last| last + 1);
+        if parts.len() != count || count > usize::from(u8::MAX) {
+            let missing: Vec<_> = (0..count)
+                .filter(|part| !parts.contains_key(part))
+                .map(|part| format!("{part:02x}"))
+                .collect();
+            warn!(
+                "Skipping split game ul.{crc:08X}.{serial}, missing parts {}",
+                missing.join(", ")
+            );
+            continue;
+        }
+
+        games.push(SplitGame {
+            crc,
+            serial,
+            parts: parts.into_values().collect(),
+        });
+    }
+
+    games
+}
+
+/// ul.cfg entry for `game` called `name`, a DVD game if `dvd` is set.
+fn entry(game: &SplitGame, name: &str, dvd: bool) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0; ENTRY_SIZE];
+
+    let name = &name.as_bytes()[..name.len().min(MAX_NAME_LEN)];
+    entry[..name.len()].copy_from_slice(name);
+    let startup = format!("ul.{}", game.serial);
+    let startup = &startup.as_bytes()[..startup.len().min(15)];
+    entry[32..32 + startup.len()].copy_from_slice(startup);
+    entry[47] = game.parts.len() as u8;
+    entry[48] = if dvd { MEDIA_DVD } else { MEDIA_CD };
+    entry[53] = 0x08;
+
+    entry
+}
+
+/// Name a game is listed under, cut to what fits in an entry without splitting a character.
+pub fn entry_name(name: &str) -> &str {
+    let mut len = name.len().min(MAX_NAME_LEN);
+    while !name.is_char_boundary(len) {
+        len -= 1;
+    }
+
+    &name[..len]
+}
+
+/// ul.cfg listing `games`, each with its name and whether it is a DVD game.
+pub fn config(games: &[(&SplitGame, String, bool)]) -> Vec<u8> {
+    games
+        .iter()
+        .flat_map(|(game, name, dvd)| entry(game, name, *dvd))
+        .collect()
+}
+
+#[test]
+fn part_names() {
+    assert_eq!(
+        parse_part_name("ul.1A2B3C4D.SLUS_203.12.0a"),
+        Some((0x1A2B3C4D, "SLUS_203.12", 10))
+    );
+    assert_eq!(parse_part_name("ul.cfg"), None);
+    assert_eq!(parse_part_name("ul.1A2B3C4D.SLUS_203.12.iso"), None);
+
+    let game = SplitGame {
+        crc: 0,
+        serial: String::from("SLUS_203.12"),
+        parts: Vec::new(),
+    };
+    let name = game.part_name("Game", 1);
+    assert_eq!(
+        parse_part_name(&name),
+        Some((crc32("Game"), "SLUS_203.12", 1))
+    );
+}
+
+#[test]
+fn missing_parts_are_skipped() {
+    let paths = [
+        "ul.00000001.SLUS_000.01.00",
+        "ul.00000001.SLUS_000.01.01",
+        "ul.00000002.SLUS_000.02.00",
+        "ul.00000002.SLUS_000.02.02",
+        "game.iso",
+    ]
+    .map(PathBuf::from);
+
+    let games = find_split_games(paths.iter().map(PathBuf::as_path));
+    assert_eq!(games.len(), 1);
+    assert_eq!(games[0].serial, "SLUS_000.01");
+    assert_eq!(games[0].parts.len(), 2);
+}
+
+#[test]
+fn config_entries() {
+    let game = SplitGame {
+        crc: 0,
+        serial: String::from("SLUS_203.12"),
+        parts: vec![PathBuf::from("a"), PathBuf::from("b")],
+    };
+    let entry = entry(&game, entry_name("Gran Turismo 3"), true);
+
+    assert_eq!(&entry[..15], b"Gran Turismo 3\0");
+    assert_eq!(&entry[32..47], b"ul.SLUS_203.12\0");
+    assert_eq!(entry[47], 2);
+    assert_eq!(entry[48], MEDIA_DVD);
+    assert_eq!(entry[53], 0x08);
+    assert_eq!(entry_name(&format!("a{}", "é".repeat(20))).len(), 31);
+}
@@ -1,11 +1,15 @@
-use std::path::{Path, PathBuf};
-
-use itertools::{EitherOrBoth, Itertools};
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+};
 
 pub fn unsigned_rounded_up_div<T>(a: T, b: T) -> T
 where
     T: num_traits::Unsigned,
 {
+    if a.is_zero() {
+        return T::zero();
+    }
     a.sub(T::one()).div(b).add(T::one())
 }
 
@@ -16,26 +20,69 @@ where
     unsigned_rounded_up_div(a, b).mul(b)
 }
 
+/// `path` past the leading components it has in common with `root`. Everything after the point
+/// they diverge is kept, and `.` components are ignored on both sides.
 pub fn relative_path_from_common_root<P>(root: P, path: P) -> PathBuf
 where
     P: AsRef<Path>,
 {
-    PathBuf::from_iter(
-        root.as_ref()
-            .components()
-            .zip_longest(path.as_ref().components())
-            .filter_map(|either| match either {
-                EitherOrBoth::Both(root_component, path_component) => {
-                    if root_component == path_component {
-                        None
-                    } else {
-                        Some(path_component)
-                    }
-                }
-                EitherOrBoth::Left(_root_component) => None,
-                EitherOrBoth::Right(path_component) => Some(path_component),
-            }),
-    )
+    let components = |path: &Path| {
+        path.components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<Vec<_>>()
+    };
+    let root = components(root.as_ref());
+    let path = components(path.as_ref());
+
+    let common = root
+        .iter()
+        .zip(&path)
+        .take_while(|(root_component, path_component)| root_component == path_component)
+        .count();
+
+    path[common..].iter().collect()
+}
+
+/// Parses a byte size with an optional `K`, `M` or `G` binary suffix, e.g. `512M`.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, shift) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 30),
+        _ => (size, 0),
+    };
+
+    let number: u64 = number
+        .parse()
+        .map_err(|err| format!("invalid size {size:?}: {err}"))?;
+
+    number
+        .checked_mul(1 << shift)
+        .filter(|&bytes| bytes > 0)
+        .ok_or_else(|| format!("invalid size {size:?}"))
+}
+
+/// Whether the error is caused by the process or the system running out of file descriptors.
+pub fn is_out_of_file_descriptors(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    const CODES: &[i32] = &[23, 24]; // ENFILE, EMFILE
+    #[cfg(windows)]
+    const CODES: &[i32] = &[4]; // ERROR_TOO_MANY_OPEN_FILES
+    #[cfg(not(any(unix, windows)))]
+    const CODES: &[i32] = &[];
+
+    matches!(err.raw_os_error(), Some(code) if CODES.contains(&code))
+}
+
+/// Creates an empty scratch directory for a test.
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("udpbd-vexfat-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    std::fs::create_dir_all(&path).unwrap();
+
+    path
 }
 
 #[test]
@@ -45,6 +92,17 @@ fn rounding_up() {
     assert_eq!(unsigned_rounded_up_div(5u32, 3), 2);
     assert_eq!(unsigned_rounded_up_div(5u32, 4), 2);
     assert_eq!(unsigned_rounded_up_div(5u32, 5), 1);
+    assert_eq!(unsigned_rounded_up_div(0u32, 5), 0);
+}
+
+#[test]
+fn size_suffixes() {
+    assert_eq!(parse_size("4096"), Ok(4096));
+    assert_eq!(parse_size("64K"), Ok(64 * 1024));
+    assert_eq!(parse_size("512m"), Ok(512 * 1024 * 1024));
+    assert_eq!(parse_size("2G"), Ok(2 * 1024 * 1024 * 1024));
+    assert!(parse_size("0").is_err());
+    assert!(parse_size("G").is_err());
 }
 
 #[test]
@@ -52,3 +110,25 @@ fn alignment() {
     assert_eq!(unsigned_align_to(5u32, 8), 8);
     assert_eq!(unsigned_align_to(15u32, 8), 16);
 }
+
+#[test]
+fn relative_paths() {
+    let relative = |root: &str, path: &str| relative_path_from_common_root(root, path);
+
+    assert_eq!(relative("/opl", "/opl"), PathBuf::new());
+    assert_eq!(
+        relative("/opl", "/opl/DVD/game.iso"),
+        PathBuf::from("DVD/game.iso")
+    );
+    assert_eq!(relative("/opl/", "/opl/DVD"), PathBuf::from("DVD"));
+    assert_eq!(relative("./opl", "opl/DVD"), PathBuf::from("DVD"));
+    assert_eq!(
+        relative("/opl/./CD", "/opl/CD/game.iso"),
+        PathBuf::from("game.iso")
+    );
+    // the rest of the path is kept once it leaves root, even where it matches root again
+    assert_eq!(
+        relative("/mnt/opl/DVD", "/mnt/games/DVD"),
+        PathBuf::from("games/DVD")
+    );
+}
@@ -1,63 +1,959 @@
 use std::{
-    collections::HashMap,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsStr,
     fs,
-    io::{self, Read, Seek},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering as AtomicOrdering},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+use anyhow::{anyhow, bail, Context};
+use clap::ValueEnum;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, info, warn};
+use unicode_normalization::UnicodeNormalization;
 use vexfatbd::VirtualExFatBlockDevice;
 use walkdir::WalkDir;
 
 use crate::{
-    protocol::RDMA_MAX_PAYLOAD,
-    utils::{relative_path_from_common_root, unsigned_align_to, unsigned_rounded_up_div},
-    Args,
+    cache::SectorCache,
+    exfat::{self, Extent},
+    iso, opl,
+    overlay::Overlay,
+    ul,
+    utils::{self, is_out_of_file_descriptors, unsigned_align_to, unsigned_rounded_up_div},
 };
 
-const BYTES_PER_SECTOR_SHIFT: u8 = 9; // 512 bytes
+/// Sector size OPL's UDPBD driver expects, 512 bytes.
+pub const DEFAULT_BYTES_PER_SECTOR_SHIFT: u8 = 9;
+/// Largest sector exFAT allows, 4 KiB.
+const MAX_BYTES_PER_SECTOR_SHIFT: u8 = 12;
 
-pub struct VexFat {
-    vexfat: VirtualExFatBlockDevice,
-    sector_count: u32,
-    pub block_shift: u8,
-    pub block_size: u16,
-    pub blocks_per_packet: u16,
-    pub blocks_per_socket: u16,
+const OUT_OF_FILE_DESCRIPTORS_HINT: &str =
+    "ran out of file descriptors, raise the open files limit (e.g. `ulimit -n 4096`) and try again";
+
+/// Default cluster size, 2048 sectors or 1 MiB.
+pub const DEFAULT_SECTORS_PER_CLUSTER_SHIFT: u8 = 11;
+/// Largest cluster exFAT allows, 32 MiB.
+const MAX_CLUSTER_SHIFT: u8 = 25;
+
+/// Default number of sectors fetched past the end of a sequential read, 64 KiB.
+pub const DEFAULT_READ_AHEAD_SECTORS: u32 = 128;
+
+/// Fewest clusters a volume is created with, enough for the root, prefix and OPL directories
+/// even when there are no files at all.
+const MIN_CLUSTER_COUNT: u64 = 32;
+
+/// Files modified this recently may still be copied in, their size is checked until it settles.
+const SETTLE_WINDOW: Duration = Duration::from_secs(5);
+/// How many times to recheck a changing file, a second apart, before giving up on it.
+const SETTLE_ATTEMPTS: usize = 5;
+
+/// How often progress is logged while mapping files.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// What to do with zero-byte files found while scanning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum EmptyFiles {
+    /// Leave them out of the volume.
+    Skip,
+    /// Map them as files without any clusters.
+    Map,
 }
 
-impl VexFat {
-    pub fn new(args: &Args) -> Self {
-        let root: std::path::PathBuf = args.root.clone();
-        let prefix = match &args.prefix {
-            Some(name) => name.clone(),
-            None => String::new(),
+/// Order the entries of each directory are mapped in, and so listed to OPL.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SortBy {
+    /// Alphabetically by name.
+    Name,
+    /// Largest files first, directories before files.
+    Size,
+    /// Most recently modified first.
+    Mtime,
+}
+
+/// Compares two entries of the same directory, ties are broken by name so the order is stable.
+fn compare_entries(sort_by: SortBy, a: &walkdir::DirEntry, b: &walkdir::DirEntry) -> Ordering {
+    let by_key = match sort_by {
+        SortBy::Name => Ordering::Equal,
+        SortBy::Size => {
+            let size = |entry: &walkdir::DirEntry| {
+                entry
+                    .metadata()
+                    .ok()
+                    .filter(|metadata| metadata.is_file())
+                    .map(|metadata| metadata.len())
+            };
+            size(a).cmp(&size(b)).reverse()
+        }
+        SortBy::Mtime => {
+            let modified = |entry: &walkdir::DirEntry| {
+                entry
+                    .metadata()
+                    .ok()
+                    .and_then(|metadata| metadata.modified().ok())
+            };
+            modified(a).cmp(&modified(b)).reverse()
+        }
+    };
+
+    by_key.then_with(|| compare_names(a.file_name(), b.file_name()))
+}
+
+/// Path with every component in NFC, so a name maps the same whether the host filesystem
+/// stores it composed or decomposed.
+fn nfc_path(path: &Path) -> PathBuf {
+    path.components()
+        .map(|component| {
+            component
+                .as_os_str()
+                .to_string_lossy()
+                .nfc()
+                .collect::<String>()
+        })
+        .collect()
+}
+
+/// Orders names by the code points of their NFC form, the same on every platform. Comparing
+/// `OsStr`s directly depends on how the platform encodes names, and macOS hands out
+/// decomposed ones, so the layout and exported images would differ between hosts.
+fn compare_names(a: &OsStr, b: &OsStr) -> Ordering {
+    let normalized = |name: &OsStr| name.to_string_lossy().nfc().collect::<String>();
+
+    // UTF-8 byte order is code point order
+    normalized(a).cmp(&normalized(b))
+}
+
+/// What to do with files and directories whose names exFAT can't store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InvalidNames {
+    /// Leave them out of the volume.
+    Skip,
+    /// Map them under a sanitized name, with the characters exFAT can't store replaced by `_`.
+    Rename,
+}
+
+/// What to map into the volume and how.
+#[derive(Clone, Debug)]
+pub struct VexFatConfig {
+    /// OPL root directory to map.
+    pub root: PathBuf,
+    /// Further directories merged into the volume, directories they have in common with root
+    /// such as `DVD` are combined.
+    pub extra_roots: Vec<PathBuf>,
+    /// Directory of images to map under `CD` or `DVD` by their size, without moving them.
+    pub staging: Option<PathBuf>,
+    /// Directory in the volume root to map everything under, the volume root itself if `None`.
+    pub prefix: Option<String>,
+    pub empty_files: EmptyFiles,
+    pub invalid_names: InvalidNames,
+    pub sort_by: SortBy,
+    /// Create the directories OPL expects in root if they're missing.
+    pub create_dirs: bool,
+    /// Map what symlinks point to, otherwise they're skipped. Broken links and links back to a
+    /// parent directory are skipped either way.
+    pub follow_symlinks: bool,
+    /// Warn if the prefix in OPL's configuration doesn't match `prefix`.
+    pub check_opl_config: bool,
+    /// File listing the files to map first, most played first.
+    pub map_order_by_popularity: Option<PathBuf>,
+    /// Map AppleDouble `._*` files and other macOS metadata instead of skipping them.
+    pub keep_sidecar_files: bool,
+    /// Log a table of the games under CD and DVD with their sizes once mapped.
+    pub summary: bool,
+    /// Read the serial and volume name of every mapped ISO image.
+    pub read_titles: bool,
+    /// Files served from memory rather than from the host, by their path relative to root.
+    pub memory_files: Vec<(String, Vec<u8>)>,
+    /// Glob patterns of files and directories to skip, matched against the path relative to
+    /// root and the name alone.
+    pub exclude: Vec<String>,
+    /// Glob patterns of the files to map, matched like `exclude`. Every file if empty.
+    pub include: Vec<String>,
+    /// Spread the files of directories holding more than this many over alphabetical
+    /// subdirectories.
+    pub shard_over: Option<usize>,
+    /// Directory to keep writes in instead of writing to the mapped files.
+    pub overlay: Option<PathBuf>,
+    /// Serve root as a prepared exFAT image or block device instead of mapping a directory.
+    pub image: bool,
+    /// Sectors to fetch past the end of a sequential read for the next one, 0 to disable.
+    pub read_ahead_sectors: u32,
+    /// Bytes of recently read sectors to keep in memory, 0 to disable.
+    pub cache_size: u64,
+    /// Cluster size as a power of two sectors, at most 32 MiB. Larger clusters mean a smaller FAT
+    /// but more slack after the end of each file.
+    pub sectors_per_cluster_shift: u8,
+    /// Sector size as a power of two bytes, from 512 to 4096. OPL expects 512.
+    pub bytes_per_sector_shift: u8,
+}
+
+impl VexFatConfig {
+    /// Maps `root` as is, the same as running the server without any options.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            extra_roots: Vec::new(),
+            staging: None,
+            prefix: None,
+            empty_files: EmptyFiles::Map,
+            invalid_names: InvalidNames::Skip,
+            sort_by: SortBy::Name,
+            create_dirs: true,
+            follow_symlinks: false,
+            check_opl_config: false,
+            map_order_by_popularity: None,
+            keep_sidecar_files: false,
+            summary: false,
+            read_titles: false,
+            memory_files: Vec::new(),
+            exclude: Vec::new(),
+            include: Vec::new(),
+            shard_over: None,
+            overlay: None,
+            image: false,
+            read_ahead_sectors: DEFAULT_READ_AHEAD_SECTORS,
+            cache_size: 0,
+            sectors_per_cluster_shift: DEFAULT_SECTORS_PER_CLUSTER_SHIFT,
+            bytes_per_sector_shift: DEFAULT_BYTES_PER_SECTOR_SHIFT,
+        }
+    }
+
+    pub fn prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    pub fn sectors_per_cluster_shift(mut self, shift: u8) -> Self {
+        self.sectors_per_cluster_shift = shift;
+        self
+    }
+
+    /// Turns off everything mapping writes outside of temp, the directories OPL expects and the
+    /// overlay, for mapping without changing anything. Returns the directories that would have
+    /// been created in root.
+    pub fn dry_run(&mut self) -> Vec<PathBuf> {
+        let missing = if self.create_dirs && !self.image {
+            OPL_DIRS
+                .iter()
+                .map(|name| self.root.join(name))
+                .filter(|path| !path.exists())
+                .collect()
+        } else {
+            Vec::new()
         };
+        self.create_dirs = false;
+        self.overlay = None;
+
+        missing
+    }
+}
+
+/// Directories OPL looks for in its root, created unless mapping root as is.
+const OPL_DIRS: [&str; 9] = [
+    "APPS", "ART", "CD", "CFG", "DVD", "CHT", "LNG", "THM", "VMC",
+];
+
+fn glob_set(patterns: &[String]) -> Result<GlobSet, globset::Error> {
+    let mut set = GlobSetBuilder::new();
+    for pattern in patterns {
+        set.add(Glob::new(pattern)?);
+    }
+
+    set.build()
+}
+
+/// Whether the path relative to root or its name alone matches one of the patterns, so
+/// `Thumbs.db` matches in every directory while `DVD/*.txt` only matches in `DVD`.
+fn glob_matches(set: &GlobSet, relative: &Path) -> bool {
+    set.is_match(relative) || relative.file_name().is_some_and(|name| set.is_match(name))
+}
+
+/// Metadata macOS leaves behind on non-HFS volumes, never useful to OPL.
+const SIDECAR_NAMES: [&str; 6] = [
+    ".DS_Store",
+    ".AppleDouble",
+    ".Spotlight-V100",
+    ".Trashes",
+    ".fseventsd",
+    ".TemporaryItems",
+];
+
+/// AppleDouble `._Foo.iso` resource forks and other macOS metadata files and directories.
+fn is_sidecar(name: &str) -> bool {
+    name.starts_with("._") || SIDECAR_NAMES.contains(&name)
+}
+
+/// Longest name exFAT can store, in UTF-16 code units.
+const MAX_NAME_LEN: usize = 255;
+
+/// Control characters and the characters exFAT reserves, `"*/:<>?\|`.
+fn is_invalid_name_char(c: char) -> bool {
+    c < ' ' || matches!(c, '"' | '*' | '/' | ':' | '<' | '>' | '?' | '\\' | '|')
+}
+
+/// Why exFAT can't store `name`, `None` if it can.
+fn invalid_name_reason(name: &str) -> Option<&'static str> {
+    if name.chars().any(is_invalid_name_char) {
+        Some("its name has characters exFAT can't store")
+    } else if name.ends_with(['.', ' ']) {
+        Some("its name ends with a dot or a space")
+    } else if name.encode_utf16().count() > MAX_NAME_LEN {
+        Some("its name is longer than exFAT allows")
+    } else {
+        None
+    }
+}
+
+/// `name` with the characters exFAT can't store replaced by `_`, cut down to the longest name
+/// exFAT allows and without trailing dots and spaces. Valid names are returned as is.
+fn sanitize_name(name: &str) -> String {
+    let mut len = 0;
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| if is_invalid_name_char(c) { '_' } else { c })
+        .take_while(|c| {
+            len += c.len_utf16();
+            len <= MAX_NAME_LEN
+        })
+        .collect();
+
+    sanitized.truncate(sanitized.trim_end_matches(['.', ' ']).len());
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+
+    sanitized
+}
+
+/// Largest image an 80 minute CD holds, staged images past it are mapped under `DVD`.
+const CD_IMAGE_MAX_SIZE: u64 = 360_000 * 2048;
+
+/// OPL directory a staged image of `size` bytes belongs in.
+fn classify_image(size: u64) -> &'static str {
+    if size <= CD_IMAGE_MAX_SIZE {
+        "CD"
+    } else {
+        "DVD"
+    }
+}
+
+/// Images directly in the staging directory, each with the path it is mapped at relative to root.
+fn staged_images(staging: &Path) -> anyhow::Result<Vec<(PathBuf, PathBuf, u64)>> {
+    let entries = fs::read_dir(staging)
+        .with_context(|| format!("Failed to read staging directory {}", staging.display()))?;
 
-        for name in [
-            "APPS", "ART", "CD", "CFG", "DVD", "CHT", "LNG", "THM", "VMC",
-        ] {
-            let path = root.join(name);
-            if path.exists() {
+    let mut images = Vec::new();
+    for entry in entries {
+        let entry = entry.context("Failed to read staging directory entry")?;
+        let path = entry.path();
+        let is_image = is_iso(&path);
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                warn!("Failed to read metadata of {}: {err}", path.display());
                 continue;
             }
+        };
+        if !is_image || !metadata.is_file() {
+            debug!("Skipping {}, only ISO images are staged", path.display());
+            continue;
+        }
+
+        let category = classify_image(metadata.len());
+        info!(
+            "Mapping {} under {category}, {} MiB",
+            path.display(),
+            metadata.len() / 1024 / 1024
+        );
+        let relative = Path::new(category).join(entry.file_name());
+        images.push((path, relative, metadata.len()));
+    }
+    images.sort_by(|(_, a, _), (_, b, _)| compare_names(a.as_os_str(), b.as_os_str()));
+
+    Ok(images)
+}
+
+/// Alphabetical subdirectories files are spread over when a directory holds too many of them.
+const SHARDS: [&str; 6] = ["A-E", "F-J", "K-O", "P-T", "U-Z", "#"];
+
+/// Shard a file name belongs to, by its first letter.
+fn shard_of(name: &str) -> &'static str {
+    match name.chars().next().unwrap_or_default().to_ascii_uppercase() {
+        'A'..='E' => SHARDS[0],
+        'F'..='J' => SHARDS[1],
+        'K'..='O' => SHARDS[2],
+        'P'..='T' => SHARDS[3],
+        'U'..='Z' => SHARDS[4],
+        _ => SHARDS[5],
+    }
+}
+
+/// Clusters needed to hold the files and directories, with room for their directory entries.
+fn cluster_count(
+    files_bytes: u64,
+    dirs_count: u64,
+    files_count: u64,
+    bytes_per_cluster: u64,
+) -> u64 {
+    let cluster_count =
+        unsigned_rounded_up_div(files_bytes, bytes_per_cluster) + (3 * (dirs_count + files_count));
+
+    unsigned_align_to(cluster_count.max(MIN_CLUSTER_COUNT), 2)
+}
+
+/// Parses a size such as `128K` into its power of two, which must be within `shifts`.
+fn parse_shift(size: &str, shifts: RangeInclusive<u8>) -> Result<u8, String> {
+    let bytes = utils::parse_size(size)?;
+    if !bytes.is_power_of_two() {
+        return Err(format!("{size:?} is not a power of two"));
+    }
+
+    let shift = bytes.trailing_zeros() as u8;
+    if !shifts.contains(&shift) {
+        return Err(format!(
+            "{size:?} is outside the {} to {} bytes exFAT allows",
+            1u32 << shifts.start(),
+            1u32 << shifts.end()
+        ));
+    }
+
+    Ok(shift)
+}
+
+/// Parses `--cluster-size` into the power of two of the cluster size in bytes.
+pub fn parse_cluster_size(size: &str) -> Result<u8, String> {
+    parse_shift(size, DEFAULT_BYTES_PER_SECTOR_SHIFT..=MAX_CLUSTER_SHIFT)
+}
+
+/// Parses `--sector-size` into the power of two of the sector size in bytes.
+pub fn parse_sector_size(size: &str) -> Result<u8, String> {
+    parse_shift(
+        size,
+        DEFAULT_BYTES_PER_SECTOR_SHIFT..=MAX_BYTES_PER_SECTOR_SHIFT,
+    )
+}
+
+/// Warns about sector sizes OPL may not handle, it is only tested with 512 byte sectors.
+fn warn_sector_size(bytes_per_sector_shift: u8) {
+    if bytes_per_sector_shift != DEFAULT_BYTES_PER_SECTOR_SHIFT {
+        warn!(
+            "Serving {} byte sectors, OPL's UDPBD driver expects 512 and may fail to mount the volume",
+            1u32 << bytes_per_sector_shift
+        );
+    }
+}
+
+/// Sector numbers above this trip drivers that treat them as signed, 1 TiB with 512 byte sectors.
+const SAFE_VOLUME_SECTORS: u64 = 1 << 31;
+
+/// Checks that a volume of `sectors` can be addressed, UDPBD requests carry 32-bit sector
+/// numbers. Volumes past what every driver handles only get a warning.
+fn check_volume_sectors(sectors: u64, bytes_per_sector: u64) -> anyhow::Result<u32> {
+    let gib = |sectors: u64| sectors * bytes_per_sector / 1024 / 1024 / 1024;
+    let Ok(sector_count) = u32::try_from(sectors) else {
+        bail!(
+            "The volume would be {} GiB, but at most {} GiB can be addressed; map less with --exclude or split the library over several smaller roots",
+            gib(sectors),
+            gib(u64::from(u32::MAX))
+        );
+    };
+
+    if sectors > SAFE_VOLUME_SECTORS {
+        warn!(
+            "The volume is {} GiB, OPL may not reach everything past {} GiB; consider splitting the library over several smaller roots",
+            gib(sectors),
+            gib(SAFE_VOLUME_SECTORS)
+        );
+    }
+
+    Ok(sector_count)
+}
+
+/// Path of a mapped file as read back from the volume, `/` separated and under the prefix.
+fn volume_path(prefix: &str, relative: &Path) -> String {
+    let relative = relative
+        .components()
+        .map(|component| sanitize_name(&component.as_os_str().to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join("/");
+
+    if prefix.is_empty() {
+        relative
+    } else {
+        format!("{prefix}/{relative}")
+    }
+}
+
+/// Top-level directory the path belongs to, e.g. `DVD` or `ART`.
+fn category_of(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let mut components = relative.components();
+
+    match (components.next(), components.next()) {
+        (Some(dir), Some(_)) => dir.as_os_str().to_string_lossy().into_owned(),
+        _ => String::from("(root)"),
+    }
+}
+
+/// Game a file relative to root belongs to, the top-level entry under `CD` or `DVD` it is in or
+/// is, e.g. `DVD/game.iso` or `CD/game` for a split game.
+fn game_of(relative: &Path) -> Option<PathBuf> {
+    let mut components = relative.components();
+    let category = components.next()?.as_os_str();
+    let entry = components.next()?.as_os_str();
+
+    ["CD", "DVD"]
+        .iter()
+        .any(|name| category.eq_ignore_ascii_case(name))
+        .then(|| Path::new(category).join(entry))
+}
+
+/// Root the walked `path` was found in.
+fn root_of<'a>(roots: &'a [PathBuf], path: &Path) -> &'a Path {
+    roots
+        .iter()
+        .find(|root| path.starts_with(root))
+        .unwrap_or(&roots[0])
+}
+
+/// Moves the files listed in `list`, most played first, ahead of the rest so they get the lowest
+/// clusters. Directories go first so parents are always mapped before their contents.
+fn order_by_popularity(
+    items: Vec<(PathBuf, bool)>,
+    roots: &[PathBuf],
+    list: &Path,
+) -> Vec<(PathBuf, bool)> {
+    let ranks: HashMap<String, usize> = match fs::read_to_string(list) {
+        Ok(list) => list
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(rank, name)| (name.replace('\\', "/"), rank))
+            .collect(),
+        Err(err) => {
+            warn!("Failed to read play counts from {}: {err}", list.display());
+            return items;
+        }
+    };
+
+    // entries can be either a path relative to root or just a file name
+    let rank_of = |path: &Path| {
+        let relative = path.strip_prefix(root_of(roots, path)).unwrap_or(path);
+        let relative = relative.to_string_lossy().replace('\\', "/");
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+
+        ranks
+            .get(&relative)
+            .or_else(|| ranks.get(&*name))
+            .copied()
+            .unwrap_or(usize::MAX)
+    };
+
+    let (dirs, mut files): (Vec<_>, Vec<_>) = items.into_iter().partition(|(_, is_file)| !is_file);
+    files.sort_by_key(|(path, _)| rank_of(path));
+
+    let listed = files
+        .iter()
+        .take_while(|(path, _)| rank_of(path) != usize::MAX)
+        .count();
+    info!("Mapping {listed} files listed in {} first", list.display());
+
+    dirs.into_iter().chain(files).collect()
+}
+
+/// File modified right before the scan, possibly still being copied.
+struct RecentFile {
+    path: PathBuf,
+    scanned_size: u64,
+    size: u64,
+    settled: bool,
+}
+
+/// Rechecks recently modified files until their size stops changing.
+fn settle_files(mut files: Vec<RecentFile>) -> Vec<RecentFile> {
+    if files.is_empty() {
+        return files;
+    }
+
+    info!(
+        "Waiting for {} recently modified files to settle",
+        files.len()
+    );
+
+    for _ in 0..SETTLE_ATTEMPTS {
+        thread::sleep(Duration::from_secs(1));
+
+        for file in files.iter_mut().filter(|file| !file.settled) {
+            match fs::metadata(&file.path) {
+                Ok(metadata) if metadata.len() == file.size => file.settled = true,
+                Ok(metadata) => file.size = metadata.len(),
+                Err(err) => warn!("Failed to read metadata: {err}"),
+            }
+        }
+
+        if files.iter().all(|file| file.settled) {
+            break;
+        }
+    }
+
+    files
+}
+
+/// What the volume is read from.
+enum Device {
+    /// Directory mapped into a virtual exFAT volume.
+    Virtual(VirtualExFatBlockDevice),
+    /// Prepared image served as is.
+    Image {
+        file: fs::File,
+        len: u64,
+        bytes_per_sector_shift: u8,
+    },
+}
+
+impl Device {
+    fn bytes_per_sector(&self) -> u16 {
+        match self {
+            Device::Virtual(vexfat) => vexfat.bytes_per_sector(),
+            Device::Image {
+                bytes_per_sector_shift,
+                ..
+            } => 1 << bytes_per_sector_shift,
+        }
+    }
+
+    fn volume_size(&self) -> u64 {
+        match self {
+            Device::Virtual(vexfat) => vexfat.volume_size(),
+            Device::Image { len, .. } => *len,
+        }
+    }
+}
+
+impl Read for Device {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Device::Virtual(vexfat) => vexfat.read(buf),
+            Device::Image { file, .. } => file.read(buf),
+        }
+    }
+}
+
+impl Seek for Device {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Device::Virtual(vexfat) => vexfat.seek(pos),
+            Device::Image { file, .. } => file.seek(pos),
+        }
+    }
+}
+
+/// Data fetched past the end of a sequential read, ready for the next one.
+struct ReadAhead {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+pub struct VexFat {
+    vexfat: Device,
+    sector_count: u32,
+    /// Host path of every mapped file, by its path on the volume.
+    files: HashMap<String, PathBuf>,
+    /// Game of every mapped image whose title was read, by its path on the volume.
+    titles: HashMap<String, iso::Title>,
+    /// File entries carrying the timestamps of the host files and directories, by volume
+    /// offset, served in place of the entries of the device.
+    timestamp_entries: BTreeMap<u64, [u8; exfat::ENTRY_SIZE]>,
+    /// Contents of the files served from memory, by the volume offset of each run of them.
+    memory_runs: BTreeMap<u64, Vec<u8>>,
+    /// Where the data of every mapped file lives on the volume, sorted by volume offset.
+    extents: Vec<Extent>,
+    /// Where writes go instead of the mapped files, if set.
+    overlay: Option<Overlay>,
+    /// Host file the last write went to, by its path on the volume, kept open for the next
+    /// packet of the write.
+    written_file: Option<(String, fs::File)>,
+    read_ahead_sectors: u32,
+    read_ahead: Option<ReadAhead>,
+    /// Byte offset the last read ended at, a read starting there is sequential.
+    last_read_end: Option<u64>,
+    cache: Option<SectorCache>,
+    /// Holds the placeholders and links the device maps, removed after the device is dropped.
+    scratch: ScratchDir,
+}
+
+/// A freshly mapped volume along with what is needed to write to it.
+pub struct Volume {
+    vexfat: Device,
+    sector_count: u32,
+    files: HashMap<String, PathBuf>,
+    /// Modification time of every mapped file and directory, by its path on the volume.
+    times: HashMap<String, SystemTime>,
+    /// Game of every mapped image whose title was read, by its path on the volume.
+    titles: HashMap<String, iso::Title>,
+    /// Contents of the files served from memory, by their path on the volume.
+    memory: HashMap<String, Vec<u8>>,
+    scratch: ScratchDir,
+}
+
+/// Directory in temp for the files the device maps in place of others, removed when dropped.
+/// Each mapping gets its own, so a rescan doesn't touch the files the previous one still maps.
+struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    fn new() -> Self {
+        static MAPPINGS: AtomicUsize = AtomicUsize::new(0);
+        let mapping = MAPPINGS.fetch_add(1, AtomicOrdering::Relaxed);
+
+        Self(std::env::temp_dir().join(format!("udpbd-vexfat-{}-{mapping}", std::process::id())))
+    }
+
+    fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        match fs::remove_dir_all(&self.0) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => warn!("Failed to remove {}: {err}", self.0.display()),
+        }
+    }
+}
+
+/// Opens the overlay in the configured directory for the layout of `vexfat`, if any.
+fn open_overlay(config: &VexFatConfig, vexfat: &VexFat) -> anyhow::Result<Option<Overlay>> {
+    let Some(dir) = &config.overlay else {
+        return Ok(None);
+    };
+
+    let overlay = Overlay::open(
+        dir,
+        vexfat.layout_fingerprint(),
+        u64::from(vexfat.vexfat.bytes_per_sector()),
+    )
+    .with_context(|| format!("Failed to open the overlay in {}", dir.display()))?;
+    info!(
+        "Writes go to the overlay in {}, {} sectors loaded",
+        dir.display(),
+        overlay.sector_count()
+    );
+
+    Ok(Some(overlay))
+}
+
+/// Opens root as an image to serve as is.
+fn open_image(config: &VexFatConfig) -> anyhow::Result<Volume> {
+    let path = &config.root;
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Failed to open image {}", path.display()))?;
+    // the metadata of block devices reports no length, seek to the end instead
+    let len = file
+        .seek(SeekFrom::End(0))
+        .with_context(|| format!("Failed to get the size of image {}", path.display()))?;
+
+    let bytes_per_sector_shift = config.bytes_per_sector_shift;
+    if bytes_per_sector_shift > MAX_BYTES_PER_SECTOR_SHIFT {
+        bail!(
+            "Sectors of {} bytes are past the 4096 exFAT allows",
+            1u32 << bytes_per_sector_shift
+        );
+    }
+    warn_sector_size(bytes_per_sector_shift);
+
+    let device = Device::Image {
+        file,
+        len,
+        bytes_per_sector_shift,
+    };
+    let sector_size = u64::from(device.bytes_per_sector());
+    if len % sector_size != 0 {
+        warn!(
+            "Image {} isn't a whole number of sectors, the trailing {} bytes are not served",
+            path.display(),
+            len % sector_size
+        );
+    }
+    let sector_count = u32::try_from(len / sector_size)
+        .map_err(|_| anyhow!("Image {} is too large to serve", path.display()))?;
+
+    info!("Serving image {}", path.display());
+    info!(" - size = {} MiB", len / 1024 / 1024);
+
+    Ok(Volume {
+        vexfat: device,
+        sector_count,
+        files: HashMap::new(),
+        times: HashMap::new(),
+        titles: HashMap::new(),
+        memory: HashMap::new(),
+        scratch: ScratchDir::new(),
+    })
+}
+
+fn entry_modified(path: &Path) -> io::Result<SystemTime> {
+    fs::metadata(path)?.modified()
+}
+
+/// Walks root and maps everything in it into a new volume, or opens it as an image.
+fn map_volume(config: &VexFatConfig) -> anyhow::Result<Volume> {
+    if config.image {
+        return open_image(config);
+    }
+
+    let scratch = ScratchDir::new();
+    let root = config.root.clone();
+    let roots: Vec<PathBuf> = std::iter::once(root.clone())
+        .chain(config.extra_roots.iter().cloned())
+        .collect();
+    for root in &roots {
+        if !root.exists() {
+            bail!(
+                "root '{}' does not exist, create it or pass an existing OPL directory",
+                root.display()
+            );
+        }
+        if !root.is_dir() {
+            bail!("root '{}' is not a directory", root.display());
+        }
+    }
+    for (i, a) in roots.iter().enumerate() {
+        for b in &roots[i + 1..] {
+            if a.starts_with(b) || b.starts_with(a) {
+                bail!(
+                    "roots '{}' and '{}' overlap, pass only one of them",
+                    a.display(),
+                    b.display()
+                );
+            }
+        }
+    }
+    if let Some(staging) = &config.staging {
+        if let Some(root) = roots
+            .iter()
+            .find(|root| staging.starts_with(root) || root.starts_with(staging))
+        {
+            bail!(
+                "staging directory '{}' overlaps root '{}', keep it outside",
+                staging.display(),
+                root.display()
+            );
+        }
+    }
+    let prefix = match &config.prefix {
+        Some(name) => name.clone(),
+        None => String::new(),
+    };
 
-            println!("Creating {}", path.display());
-            fs::create_dir(path).expect("failed to create default OPL directories");
+    for name in OPL_DIRS {
+        let path = root.join(name);
+        if !config.create_dirs || path.exists() {
+            continue;
         }
 
-        let mut total_files_bytes = 0;
-        let mut total_files_count = 0;
-        let mut total_dirs_count = 0;
-        let mut items = Vec::new();
+        info!("Creating {}", path.display());
+        fs::create_dir(&path).with_context(|| {
+            format!(
+                "Failed to create {}, pass --no-create-dirs to map root as is",
+                path.display()
+            )
+        })?;
+    }
+
+    if config.check_opl_config {
+        opl::check_config_prefix(&root, config.prefix.as_deref());
+    }
+
+    let exclude = glob_set(&config.exclude).context("Invalid exclude pattern")?;
+    let include = glob_set(&config.include).context("Invalid include pattern")?;
 
-        for entry in WalkDir::new(&args.root)
+    let mut total_files_bytes = 0;
+    let mut total_files_count = 0;
+    let mut total_dirs_count = 0;
+    let mut empty_files_count = 0;
+    let mut sidecar_count = 0;
+    let mut invalid_name_count = 0;
+    let mut symlink_count = 0;
+    let mut excluded_count = 0;
+    let mut categories = BTreeMap::<String, (usize, u64)>::new();
+    let mut recently_modified = Vec::new();
+    // everything is collected before mapping, the volume geometry depends on the totals and
+    // settling, popularity ordering and sharding all need the whole list. An entry costs about
+    // as much as the host path it holds, less than `files` keeps for the life of the volume.
+    let mut items = Vec::new();
+
+    // roots are walked one after another, directories they have in common are merged
+    for root in &roots {
+        for entry in WalkDir::new(&root)
             .min_depth(1)
             .contents_first(false)
-            .sort_by_file_name()
+            .sort_by(|a, b| compare_entries(config.sort_by, a, b))
+            .follow_links(config.follow_symlinks)
+            .into_iter()
+            .filter_entry(|entry| {
+                let name = entry.file_name().to_string_lossy();
+                if !config.keep_sidecar_files && is_sidecar(&name) {
+                    sidecar_count += 1;
+                    return false;
+                }
+
+                let relative = entry.path().strip_prefix(&root).unwrap_or(entry.path());
+                let excluded = glob_matches(&exclude, relative)
+                    || (!config.include.is_empty()
+                        && !entry.file_type().is_dir()
+                        && !glob_matches(&include, relative));
+                if excluded {
+                    debug!("Excluding {}", entry.path().display());
+                    excluded_count += 1;
+                    return false;
+                }
+
+                // only reported as a symlink when links aren't followed
+                if entry.file_type().is_symlink() {
+                    debug!("Skipping symlink {}", entry.path().display());
+                    symlink_count += 1;
+                    return false;
+                }
+
+                if let Some(reason) = invalid_name_reason(&name) {
+                    let path = entry.path().display();
+                    if config.invalid_names == InvalidNames::Rename {
+                        warn!("Renaming {path} to {}, {reason}", sanitize_name(&name));
+                    } else {
+                        warn!("Skipping {path}, {reason}");
+                        invalid_name_count += 1;
+                        return false;
+                    }
+                }
+
+                true
+            })
         {
             let entry = match entry {
                 Ok(entry) => entry,
                 Err(err) => {
-                    eprintln!("Failed to read entry: {err}");
+                    if err.io_error().is_some_and(is_out_of_file_descriptors) {
+                        bail!("Failed to read entry: {OUT_OF_FILE_DESCRIPTORS_HINT}");
+                    }
+                    let path = err.path().unwrap_or(&root).display();
+                    if let Some(ancestor) = err.loop_ancestor() {
+                        warn!("Skipping {path}, it links back to {}", ancestor.display());
+                    } else if err.io_error().map(io::Error::kind) == Some(io::ErrorKind::NotFound) {
+                        warn!("Skipping broken symlink {path}");
+                    } else {
+                        warn!("Failed to read entry: {err}");
+                    }
                     continue;
                 }
             };
@@ -67,20 +963,41 @@ impl VexFat {
                 let metadata = match entry.metadata() {
                     Ok(metadata) => metadata,
                     Err(err) => {
-                        eprintln!("Failed to read metadata: {err}");
+                        if err.io_error().is_some_and(is_out_of_file_descriptors) {
+                            bail!("Failed to read metadata: {OUT_OF_FILE_DESCRIPTORS_HINT}");
+                        }
+                        warn!("Failed to read metadata: {err}");
                         continue;
                     }
                 };
 
-                #[cfg(target_os = "linux")]
-                {
-                    use std::os::unix::fs::MetadataExt;
-                    total_files_bytes += metadata.size();
+                if metadata.len() == 0 {
+                    empty_files_count += 1;
+
+                    if config.empty_files == EmptyFiles::Skip {
+                        info!("Skipping empty file {}", path.display());
+                        continue;
+                    }
                 }
-                #[cfg(target_os = "windows")]
-                {
-                    use std::os::windows::fs::MetadataExt;
-                    total_files_bytes += metadata.file_size();
+
+                total_files_bytes += metadata.len();
+
+                let category = categories.entry(category_of(&root, path)).or_default();
+                category.0 += 1;
+                category.1 += metadata.len();
+
+                let modified_recently = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|modified| modified.elapsed().ok())
+                    .is_some_and(|age| age < SETTLE_WINDOW);
+                if modified_recently {
+                    recently_modified.push(RecentFile {
+                        path: path.to_owned(),
+                        scanned_size: metadata.len(),
+                        size: metadata.len(),
+                        settled: false,
+                    });
                 }
 
                 total_files_count += 1;
@@ -90,130 +1007,1823 @@ impl VexFat {
 
             items.push((path.to_owned(), path.is_file()));
         }
+    }
 
-        let sector_size = 1 << BYTES_PER_SECTOR_SHIFT;
-        let sectors_per_cluster_shift = 11; // 2048 sectors
-        let sectors_per_cluster = 1 << sectors_per_cluster_shift;
-        let bytes_per_cluster = sectors_per_cluster * sector_size;
-
-        let cluster_count = unsigned_rounded_up_div(total_files_bytes, bytes_per_cluster)
-            + (3 * (total_dirs_count + total_files_count));
-        let cluster_count = unsigned_align_to(cluster_count, 2);
-        let sector_count = cluster_count * sectors_per_cluster;
+    // staged images and generated files are mapped at a path relative to root rather than by
+    // where they are on the host
+    let mut relocated = HashMap::new();
+    if let Some(staging) = &config.staging {
+        for (path, relative, size) in staged_images(staging)? {
+            let category = categories
+                .entry(relative.parent().unwrap().to_string_lossy().into_owned())
+                .or_default();
+            category.0 += 1;
+            category.1 += size;
+            total_files_bytes += size;
+            total_files_count += 1;
 
-        let mut vexfat = vexfatbd::VirtualExFatBlockDevice::new(
-            BYTES_PER_SECTOR_SHIFT,
-            sectors_per_cluster_shift,
-            cluster_count as _,
-        )
-        .unwrap();
+            items.push((path.clone(), true));
+            relocated.insert(path, relative);
+        }
+    }
 
-        println!("Mapping files");
+    for file in settle_files(recently_modified) {
+        let category = categories
+            .entry(category_of(root_of(&roots, &file.path), &file.path))
+            .or_default();
+        total_files_bytes -= file.scanned_size;
+        category.1 -= file.scanned_size;
 
-        let prefix_cluster = match &args.prefix {
-            Some(name) => vexfat.add_directory_in_root(name).unwrap(),
-            None => vexfat.root_directory_cluster(),
-        };
+        if file.settled {
+            total_files_bytes += file.size;
+            category.1 += file.size;
+        } else {
+            warn!(
+                "Skipping {}, it is still being written to",
+                file.path.display()
+            );
+            total_files_count -= 1;
+            category.0 -= 1;
+            items.retain(|(path, _)| *path != file.path);
+        }
+    }
 
-        let mut dirpath_to_cluster = HashMap::from([(root.clone(), prefix_cluster)]);
+    let mut memory_files: Vec<_> = config
+        .memory_files
+        .iter()
+        .map(|(path, data)| (PathBuf::from(path), data.clone()))
+        .collect();
 
-        for (path, is_file) in items {
-            let parent = path.parent().unwrap().to_owned();
-            let parent_cluster = dirpath_to_cluster.get(&parent).cloned().unwrap();
+    // OPL only finds split games listed in ul.cfg, list the ones in root unless it has one
+    if !roots.iter().any(|root| root.join("ul.cfg").exists()) {
+        let parts = items
+            .iter()
+            .filter(|(path, is_file)| *is_file && path.parent() == Some(root_of(&roots, path)))
+            .map(|(path, _)| path.as_path());
+        let games = ul::find_split_games(parts);
 
-            if is_file {
-                if let Err(err) = vexfat.map_file(parent_cluster, &path) {
-                    println!("! Failed to map file {}: {:?}", path.display(), err);
+        if !games.is_empty() {
+            // the device names entries after the host file, parts are renamed after the name the
+            // game is listed under by mapping links to them
+            let links = scratch.join("ul");
+            let mut renamed = HashMap::new();
+            let mut entries = Vec::new();
+            for game in &games {
+                let name = match iso::read_title(&game.parts[0]) {
+                    Ok(Some(title)) => title.name,
+                    _ => game.serial.clone(),
+                };
+                let name = ul::entry_name(&name).to_owned();
+                for (index, part) in game.parts.iter().enumerate() {
+                    let part_name = game.part_name(&name, index);
+                    let link = link_file(&links, renamed.len(), &part_name, part)
+                        .with_context(|| format!("Failed to link {}", part.display()))?;
+                    relocated.insert(link.clone(), PathBuf::from(part_name));
+                    renamed.insert(part.clone(), link);
                 }
-            } else {
-                let name: &str = path.file_name().unwrap().to_str().unwrap();
 
-                match vexfat.add_directory(parent_cluster, name) {
-                    Ok(dir_cluster) => {
-                        dirpath_to_cluster.insert(path.to_owned(), dir_cluster);
-                    }
-                    Err(err) => {
-                        println!("! Failed to map directory {}: {:?}", path.display(), err);
-                    }
-                }
+                let size: u64 = game
+                    .parts
+                    .iter()
+                    .filter_map(|part| fs::metadata(part).ok())
+                    .map(|metadata| metadata.len())
+                    .sum();
+                info!(
+                    "Listing split game {name} ({}) of {} parts in ul.cfg",
+                    game.serial,
+                    game.parts.len()
+                );
+                entries.push((game, name, classify_image(size) == "DVD"));
             }
 
-            let relative = relative_path_from_common_root(&root, &path);
-            println!(" - ro:vexfat:{}/{}", prefix, relative.display());
-        }
-
-        println!("Emulating read-only exFAT block device");
-        println!(" - size = {} MiB", vexfat.volume_size() / 1024 / 1024);
-
-        Self {
-            vexfat,
-            sector_count: sector_count as u32,
-            block_shift: 0,
-            block_size: 0,
-            blocks_per_packet: 0,
-            blocks_per_socket: 0,
+            memory_files.push((PathBuf::from("ul.cfg"), ul::config(&entries)));
+            for (path, _) in &mut items {
+                if let Some(link) = renamed.remove(path) {
+                    *path = link;
+                }
+            }
         }
     }
 
-    pub fn seek(&mut self, sector: u32) -> io::Result<()> {
-        let offset = u64::from(sector) * u64::from(self.sector_size());
+    // the device only maps host files, files served from memory are backed by sparse
+    // placeholders of the same size whose contents are never read
+    let placeholders = scratch.join("memory");
+    let mut memory = HashMap::new();
+    for (index, (relative, data)) in memory_files.into_iter().enumerate() {
+        let name = relative.file_name().unwrap_or_default();
+        let placeholder = placeholder_file(&placeholders, index, name, data.len())
+            .with_context(|| format!("Failed to create placeholder for {}", relative.display()))?;
 
-        self.vexfat
-            .seek(std::io::SeekFrom::Start(offset))
-            .map(|_| ())
+        let category = categories
+            .entry(category_of(Path::new(""), &relative))
+            .or_default();
+        category.0 += 1;
+        category.1 += data.len() as u64;
+        total_files_bytes += data.len() as u64;
+        total_files_count += 1;
+
+        items.push((placeholder.clone(), true));
+        relocated.insert(placeholder, relative.clone());
+        memory.insert(volume_path(&prefix, &relative), data);
     }
 
-    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
-        self.vexfat.read_exact(buf).map(|_| ())
+    if let Some(list) = &config.map_order_by_popularity {
+        items = order_by_popularity(items, &roots, list);
     }
 
-    pub fn write(&mut self, _: &[u8]) -> io::Result<()> {
-        // TODO
-        Ok(())
+    let mut sharded_dirs = HashSet::new();
+    if let Some(limit) = config.shard_over {
+        let mut files_per_dir = HashMap::<&Path, usize>::new();
+        for (path, is_file) in &items {
+            if *is_file {
+                *files_per_dir.entry(path.parent().unwrap()).or_default() += 1;
+            }
+        }
+
+        for (dir, files) in files_per_dir {
+            if files > limit {
+                info!(
+                    "Sharding {} files in {} into alphabetical subdirectories",
+                    files,
+                    dir.display()
+                );
+                sharded_dirs.insert(dir.to_owned());
+            }
+        }
+        total_dirs_count += (SHARDS.len() * sharded_dirs.len()) as u64;
     }
 
-    pub fn sector_size(&self) -> u16 {
-        self.vexfat.bytes_per_sector()
+    let bytes_per_sector_shift = config.bytes_per_sector_shift;
+    if !(DEFAULT_BYTES_PER_SECTOR_SHIFT..=MAX_BYTES_PER_SECTOR_SHIFT)
+        .contains(&bytes_per_sector_shift)
+    {
+        bail!(
+            "Sectors of {} bytes are outside the 512 to 4096 exFAT allows",
+            1u64 << bytes_per_sector_shift
+        );
     }
+    warn_sector_size(bytes_per_sector_shift);
+    let sector_size = 1 << bytes_per_sector_shift;
+    let sectors_per_cluster_shift = config.sectors_per_cluster_shift;
+    if bytes_per_sector_shift + sectors_per_cluster_shift > MAX_CLUSTER_SHIFT {
+        bail!(
+            "Clusters of {} bytes are past the 32 MiB exFAT allows",
+            1u64 << (bytes_per_sector_shift + sectors_per_cluster_shift)
+        );
+    }
+    let sectors_per_cluster = 1 << sectors_per_cluster_shift;
+    let bytes_per_cluster = sectors_per_cluster * sector_size;
 
-    pub fn sector_count(&self) -> u32 {
-        self.sector_count
+    let cluster_count = cluster_count(
+        total_files_bytes,
+        total_dirs_count,
+        total_files_count,
+        bytes_per_cluster,
+    );
+    // fail before mapping anything, the cluster heap alone must already be addressable
+    let heap_sectors = cluster_count << sectors_per_cluster_shift;
+    if heap_sectors > u64::from(u32::MAX) {
+        check_volume_sectors(heap_sectors, sector_size)?;
     }
 
-    pub fn set_block_shift(&mut self, shift: u8) {
-        if shift == self.block_shift {
-            return;
+    let mut vexfat = vexfatbd::VirtualExFatBlockDevice::new(
+        bytes_per_sector_shift,
+        sectors_per_cluster_shift,
+        cluster_count as _,
+    )
+    .map_err(|err| {
+        anyhow!(
+            "Failed to create a volume of {cluster_count} clusters of {bytes_per_cluster} bytes: {err:?}"
+        )
+    })?;
+
+    info!("Mapping {total_files_count} files");
+
+    let prefix_cluster = match &config.prefix {
+        Some(name) => vexfat
+            .add_directory_in_root(name)
+            .map_err(|err| anyhow!("Failed to create prefix directory {name}: {err:?}"))?,
+        None => vexfat.root_directory_cluster(),
+    };
+
+    // clusters of the mapped directories by path relative to their root, shared between roots
+    let mut dirpath_to_cluster = HashMap::from([(PathBuf::new(), prefix_cluster)]);
+    let mut files = HashMap::new();
+    let mut times = HashMap::new();
+    let mut titles = HashMap::new();
+    // exFAT compares names case-insensitively, upper-cased names of every directory's entries
+    let mut names_in_dir = HashMap::<_, HashSet<String>>::new();
+    // bytes of each game under CD and DVD by its path on the volume
+    let mut games = BTreeMap::<String, u64>::new();
+    // bytes lost between the end of each file and the end of its last cluster
+    let mut slack_bytes = 0;
+
+    let nfc_links = scratch.join("nfc");
+    let mut nfc_link_count = 0;
+
+    let mapping_started = Instant::now();
+    let mut last_progress = mapping_started;
+    let mut files_done = 0u64;
+
+    for (path, is_file) in items {
+        if is_file {
+            files_done += 1;
+
+            if last_progress.elapsed() >= PROGRESS_INTERVAL {
+                last_progress = Instant::now();
+                // mapping cost is per file rather than per byte, estimate by count
+                let elapsed = mapping_started.elapsed();
+                let left =
+                    elapsed.mul_f64((total_files_count - files_done) as f64 / files_done as f64);
+                info!(
+                    "Mapped {files_done}/{total_files_count} files, {}s elapsed, about {}s left",
+                    elapsed.as_secs(),
+                    left.as_secs()
+                );
+            }
         }
 
-        self.block_shift = shift;
-        self.block_size = 1 << (shift + 2);
-        self.blocks_per_packet = RDMA_MAX_PAYLOAD as u16 / self.block_size;
-        self.blocks_per_socket = self.sector_size() / self.block_size;
-        println!("Block size changed to {}", self.block_size);
-    }
-
-    pub fn set_block_shift_sectors(&mut self, sectors: u16) {
-        // Optimize for:
-        // - the least number of network packets
-        // - the largest block size (faster on the PS2)
-        let size = u32::from(sectors) * u32::from(self.sector_size());
-        let packets_min = (size + 1440 - 1) / 1440;
-        let packets_128 = (size + 1408 - 1) / 1408;
-        let packets_256 = (size + 1280 - 1) / 1280;
-        let packets_512 = (size + 1024 - 1) / 1024;
-
-        let shift = {
-            if packets_512 == packets_min {
-                7 // 512 byte blocks
-            } else if packets_256 == packets_min {
-                6 // 256 byte blocks
-            } else if packets_128 == packets_min {
-                5 // 128 byte blocks
+        let parent = path.parent().unwrap().to_owned();
+        let relative_path = match relocated.get(&path) {
+            Some(relative) => nfc_path(relative),
+            None => nfc_path(path.strip_prefix(root_of(&roots, &path)).unwrap()),
+        };
+        let relative_parent = relative_path.parent().unwrap().to_owned();
+        let Some(mut parent_cluster) = dirpath_to_cluster.get(&relative_parent).cloned() else {
+            if relocated.contains_key(&path) {
+                warn!(
+                    "Skipping {}, root has no {} directory to map it in",
+                    path.display(),
+                    relative_parent.display()
+                );
+            }
+            // otherwise the parent failed to map and was already reported
+            continue;
+        };
+        let name = sanitize_name(&relative_path.file_name().unwrap().to_string_lossy());
+        let mut mapped_path = relative_path.clone();
+
+        if !is_file && dirpath_to_cluster.contains_key(&relative_path) {
+            debug!(
+                "Merging {} into the same directory of another root",
+                path.display()
+            );
+            continue;
+        }
+
+        if is_file && sharded_dirs.contains(&parent) {
+            let shard = shard_of(&name);
+            let shard_path = relative_parent.join(shard);
+
+            parent_cluster = match dirpath_to_cluster.get(&shard_path) {
+                Some(&cluster) => cluster,
+                None => {
+                    let cluster = vexfat.add_directory(parent_cluster, shard).map_err(|err| {
+                        anyhow!(
+                            "Failed to create shard directory {}: {err:?}",
+                            shard_path.display()
+                        )
+                    })?;
+                    dirpath_to_cluster.insert(shard_path.clone(), cluster);
+                    cluster
+                }
+            };
+            mapped_path = shard_path.join(relative_path.file_name().unwrap());
+        }
+
+        let relative = mapped_path;
+        if let Some(other) = files.get(&volume_path(&prefix, &relative)) {
+            warn!(
+                "Skipping {}, {} from another root is already mapped there",
+                path.display(),
+                other.display()
+            );
+            continue;
+        }
+        if !names_in_dir
+            .entry(parent_cluster)
+            .or_default()
+            .insert(name.to_uppercase())
+        {
+            warn!(
+                "Skipping {}, another entry in the same directory differs from it only in case or Unicode form",
+                path.display()
+            );
+            continue;
+        }
+
+        if is_file {
+            // the device names entries after the host file, decomposed or sanitized names are
+            // mapped through a link with the name on the volume
+            let mut mapped_file = path.clone();
+            if path.file_name() != Some(OsStr::new(&name)) {
+                match link_file(&nfc_links, nfc_link_count, &name, &path) {
+                    Ok(link) => {
+                        nfc_link_count += 1;
+                        mapped_file = link;
+                    }
+                    Err(err) => warn!(
+                        "Failed to link {} under its name on the volume, mapping it as is: {err}",
+                        path.display()
+                    ),
+                }
+            }
+
+            if let Err(err) = vexfat.map_file(parent_cluster, &mapped_file) {
+                // mapping errors lack the OS error, reopen the file to detect fd exhaustion
+                if let Err(open_err) = fs::File::open(&path) {
+                    if is_out_of_file_descriptors(&open_err) {
+                        bail!(
+                            "Failed to map file {}: {OUT_OF_FILE_DESCRIPTORS_HINT}",
+                            path.display()
+                        );
+                    }
+                }
+                warn!("Failed to map file {}: {:?}", path.display(), err);
             } else {
-                3 //  32 byte blocks
+                files.insert(volume_path(&prefix, &relative), path.clone());
+                if config.read_titles && is_iso(&path) {
+                    match iso::read_title(&path) {
+                        Ok(Some(title)) => {
+                            info!("Mapped {} ({})", title.serial, title.name);
+                            titles.insert(volume_path(&prefix, &relative), title);
+                        }
+                        Ok(None) => debug!("No PS2 title found in {}", path.display()),
+                        Err(err) => warn!("Failed to read title of {}: {err}", path.display()),
+                    }
+                }
+                let size = fs::metadata(&path).map_or(0, |metadata| metadata.len());
+                slack_bytes += unsigned_align_to(size, bytes_per_cluster) - size;
+                if let Some(game) = game_of(&relative_path) {
+                    *games.entry(volume_path(&prefix, &game)).or_default() += size;
+                }
+                if let Ok(modified) = entry_modified(&path) {
+                    times.insert(volume_path(&prefix, &relative), modified);
+                }
+            }
+        } else {
+            match vexfat.add_directory(parent_cluster, &name) {
+                Ok(dir_cluster) => {
+                    dirpath_to_cluster.insert(relative_path, dir_cluster);
+                    if let Ok(modified) = entry_modified(&path) {
+                        times.insert(volume_path(&prefix, &relative), modified);
+                    }
+                }
+                Err(err) => {
+                    warn!("Failed to map directory {}: {:?}", path.display(), err);
+                }
+            }
+        }
+
+        debug!(" - ro:vexfat:{}/{}", prefix, relative.display());
+    }
+
+    info!("Emulating read-only exFAT block device");
+    info!(" - size = {} MiB", vexfat.volume_size() / 1024 / 1024);
+    info!(
+        " - cluster size = {bytes_per_cluster} bytes, {} MiB of slack after the ends of files",
+        slack_bytes / 1024 / 1024
+    );
+    if empty_files_count > 0 {
+        let action = match config.empty_files {
+            EmptyFiles::Skip => "skipped",
+            EmptyFiles::Map => "mapped",
+        };
+        info!(" - {empty_files_count} empty files {action}");
+    }
+    if sidecar_count > 0 {
+        info!(" - {sidecar_count} macOS sidecar files skipped");
+    }
+    if excluded_count > 0 {
+        info!(" - {excluded_count} files and directories excluded");
+    }
+    if symlink_count > 0 {
+        info!(" - {symlink_count} symlinks skipped, pass --follow-symlinks to map them");
+    }
+    if invalid_name_count > 0 {
+        info!(" - {invalid_name_count} files with names exFAT can't store skipped");
+    }
+
+    info!("Files per category");
+    for (category, (files, bytes)) in &categories {
+        info!(
+            " - {category:<8} {files:>6} files {:>8} MiB",
+            bytes / 1024 / 1024
+        );
+    }
+
+    if config.summary {
+        log_games(&games);
+    }
+
+    let vexfat = Device::Virtual(vexfat);
+
+    // report the whole volume including the boot region and FAT, not just the cluster heap,
+    // so the device size matches the volume length in the boot sector
+    let bytes_per_sector = u64::from(vexfat.bytes_per_sector());
+    let sector_count =
+        check_volume_sectors(vexfat.volume_size() / bytes_per_sector, bytes_per_sector)?;
+
+    Ok(Volume {
+        vexfat,
+        sector_count,
+        files,
+        times,
+        titles,
+        memory,
+        scratch,
+    })
+}
+
+/// Sparse file of `len` bytes for the device to map in place of a file served from memory.
+/// Named `name` so the entry gets the right name, each in its own directory so names can repeat.
+fn placeholder_file(dir: &Path, index: usize, name: &OsStr, len: usize) -> io::Result<PathBuf> {
+    let dir = dir.join(index.to_string());
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(name);
+    fs::File::create(&path)?.set_len(len as u64)?;
+
+    Ok(path)
+}
+
+/// Link called `name` to `target`, for the device to map `target` under another name.
+fn link_file(dir: &Path, index: usize, name: &str, target: &Path) -> io::Result<PathBuf> {
+    let dir = dir.join(index.to_string());
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(name);
+    if path.symlink_metadata().is_ok() {
+        fs::remove_file(&path)?;
+    }
+
+    match fs::hard_link(target, &path) {
+        Ok(()) => {}
+        // temp may be on another filesystem than the target
+        #[cfg(unix)]
+        Err(_) => std::os::unix::fs::symlink(target, &path)?,
+        #[cfg(not(unix))]
+        Err(err) => return Err(err),
+    }
+
+    Ok(path)
+}
+
+fn is_iso(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("iso"))
+}
+
+/// Logs each game with its size and path on the volume, followed by the total.
+fn log_games(games: &BTreeMap<String, u64>) {
+    let width = games.keys().map(String::len).max().unwrap_or(0);
+    let total: u64 = games.values().sum();
+
+    info!("Games mapped");
+    for (path, bytes) in games {
+        info!(" - {path:<width$} {:>8} MiB", bytes / 1024 / 1024);
+    }
+    info!(
+        " - {:<width$} {:>8} MiB in {} games",
+        "total",
+        total / 1024 / 1024,
+        games.len()
+    );
+}
+
+impl VexFat {
+    pub fn new(config: &VexFatConfig) -> anyhow::Result<Self> {
+        Self::from_volume(map_volume(config)?, config)
+    }
+
+    /// Walks root again and rebuilds the volume, picking up added or removed files.
+    /// The volume geometry may change.
+    pub fn rescan(&mut self, config: &VexFatConfig) -> anyhow::Result<()> {
+        let volume = Self::scan(config)?;
+        self.replace(volume, config)
+    }
+
+    /// Maps root into a new volume without touching the one being served, so it can be done
+    /// on another thread while serving goes on. Swapped in with [`VexFat::replace`].
+    pub fn scan(config: &VexFatConfig) -> anyhow::Result<Volume> {
+        map_volume(config)
+    }
+
+    /// Serves `volume` from now on in place of the current one, reopening the overlay for it.
+    /// The current volume is kept if that fails.
+    pub fn replace(&mut self, volume: Volume, config: &VexFatConfig) -> anyhow::Result<()> {
+        *self = Self::from_volume(volume, config)?;
+        Ok(())
+    }
+
+    fn from_volume(volume: Volume, config: &VexFatConfig) -> anyhow::Result<Self> {
+        let Volume {
+            vexfat,
+            sector_count,
+            files,
+            times,
+            titles,
+            memory,
+            scratch,
+        } = volume;
+        let cache = (config.cache_size > 0).then(|| {
+            SectorCache::new((config.cache_size / u64::from(vexfat.bytes_per_sector())) as usize)
+        });
+
+        let mut vexfat = Self {
+            vexfat,
+            sector_count,
+            files,
+            titles,
+            timestamp_entries: BTreeMap::new(),
+            memory_runs: BTreeMap::new(),
+            extents: Vec::new(),
+            overlay: None,
+            written_file: None,
+            read_ahead_sectors: config.read_ahead_sectors,
+            read_ahead: None,
+            last_read_end: None,
+            cache,
+            scratch,
+        };
+        vexfat.stamp_entries(&times);
+        vexfat.locate_files();
+        vexfat.place_memory_files(&memory);
+        // only once the files are located, the overlay belongs to where they landed
+        vexfat.overlay = open_overlay(config, &vexfat)?;
+
+        Ok(vexfat)
+    }
+
+    /// Identifies the layout of the volume, its size and where every file landed, so writes
+    /// kept for one layout aren't applied to another. Stable across runs and platforms.
+    fn layout_fingerprint(&self) -> u64 {
+        // FNV-1a
+        let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+        let mut feed = |bytes: &[u8]| {
+            for &byte in bytes {
+                hash = (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        };
+
+        feed(&self.vexfat.volume_size().to_le_bytes());
+        feed(&self.vexfat.bytes_per_sector().to_le_bytes());
+        for extent in &self.extents {
+            feed(extent.path.as_bytes());
+            feed(&[0]);
+            feed(&extent.volume_offset.to_le_bytes());
+            feed(&extent.file_offset.to_le_bytes());
+            feed(&extent.len.to_le_bytes());
+        }
+        hash
+    }
+
+    /// Gives the entries of the mapped files and directories their host timestamps, the device
+    /// doesn't take any and dates everything the same.
+    fn stamp_entries(&mut self, times: &HashMap<String, SystemTime>) {
+        if times.is_empty() {
+            return;
+        }
+
+        match exfat::timestamp_entries(self, times) {
+            Ok(entries) => self.timestamp_entries = entries,
+            Err(err) => warn!("Failed to set file timestamps, all entries keep the default: {err}"),
+        }
+    }
+
+    /// Works out where the data of the mapped files lives on the volume, once per mapping, for
+    /// writes and for telling which file a failed read belongs to.
+    fn locate_files(&mut self) {
+        if matches!(self.vexfat, Device::Image { .. }) {
+            return;
+        }
+
+        match exfat::file_extents(self) {
+            Ok(extents) => self.extents = extents,
+            Err(err) => {
+                warn!("Failed to locate the mapped files on the volume, writes to them fail: {err}")
+            }
+        }
+    }
+
+    /// Splits the contents of the files served from memory into runs to serve in place of the
+    /// placeholders.
+    fn place_memory_files(&mut self, memory: &HashMap<String, Vec<u8>>) {
+        for extent in &self.extents {
+            if let Some(data) = memory.get(&extent.path) {
+                let from = extent.file_offset as usize;
+                let run = data[from..from + extent.data_len as usize].to_vec();
+                self.memory_runs.insert(extent.volume_offset, run);
+            }
+        }
+    }
+
+    /// Copies the parts of files served from memory that fall in `buf`, read from `start`.
+    fn apply_memory_files(&self, start: u64, buf: &mut [u8]) {
+        let end = start + buf.len() as u64;
+
+        for (&offset, run) in self.memory_runs.range(..end) {
+            let run_end = offset + run.len() as u64;
+            if run_end <= start {
+                continue;
             }
+            let from = offset.max(start);
+            let to = run_end.min(end);
+            buf[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&run[(from - offset) as usize..(to - offset) as usize]);
+        }
+    }
+
+    /// Copies the parts of timestamped entries that fall in `buf`, read from `start`.
+    fn apply_timestamp_entries(&self, start: u64, buf: &mut [u8]) {
+        let end = start + buf.len() as u64;
+        let first = start.saturating_sub(exfat::ENTRY_SIZE as u64 - 1);
+
+        for (&offset, entry) in self.timestamp_entries.range(first..end) {
+            let from = offset.max(start);
+            let to = (offset + exfat::ENTRY_SIZE as u64).min(end);
+            buf[(from - start) as usize..(to - start) as usize]
+                .copy_from_slice(&entry[(from - offset) as usize..(to - offset) as usize]);
+        }
+    }
+
+    pub fn seek(&mut self, sector: u32) -> io::Result<()> {
+        self.seek_offset(u64::from(sector) * u64::from(self.sector_size()))
+    }
+
+    /// Seek to a byte offset that doesn't have to be sector aligned.
+    pub fn seek_offset(&mut self, offset: u64) -> io::Result<()> {
+        self.vexfat.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+
+    /// Reads the next `buf.len()` bytes. The position always advances by exactly that much, even
+    /// if the read fails partway, so the following reads of a request stay aligned.
+    pub fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let start = self.vexfat.stream_position()?;
+        let end = start + buf.len() as u64;
+
+        let mut result = self.read_cached(start, buf);
+        if result.is_err() {
+            self.vexfat.seek(SeekFrom::Start(end))?;
+            return result;
+        }
+
+        self.apply_timestamp_entries(start, buf);
+        self.apply_memory_files(start, buf);
+        if let Some(overlay) = &mut self.overlay {
+            result = overlay.apply(start, buf);
+        }
+
+        result
+    }
+
+    /// Reads `buf` at `start`, the device is positioned there. Cached sectors are served from
+    /// memory, the span of sectors missing from the cache is read from the device and cached.
+    fn read_cached(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        let sector_size = u64::from(self.sector_size());
+        let end = start + buf.len() as u64;
+        let sectors = start / sector_size..end.div_ceil(sector_size);
+
+        let sector_count = u64::from(self.sector_count);
+        // reads running off the end of the volume are left to the device to fail
+        let Some(cache) = self.cache.as_mut().filter(|_| sectors.end <= sector_count) else {
+            return self.read_device(start, buf);
         };
 
-        self.set_block_shift(shift);
+        let mut covered = vec![0; ((sectors.end - sectors.start) * sector_size) as usize];
+        for (sector, data) in sectors
+            .clone()
+            .zip(covered.chunks_exact_mut(sector_size as usize))
+        {
+            if let Some(cached) = cache.get(sector) {
+                data.copy_from_slice(cached);
+            }
+        }
+
+        let first_missing = sectors.clone().find(|&sector| !cache.contains(sector));
+        let last_missing = sectors
+            .clone()
+            .rev()
+            .find(|&sector| !cache.contains(sector));
+        if let (Some(first), Some(last)) = (first_missing, last_missing) {
+            cache.misses += 1;
+
+            let from = ((first - sectors.start) * sector_size) as usize;
+            let to = ((last + 1 - sectors.start) * sector_size) as usize;
+            self.vexfat.seek(SeekFrom::Start(first * sector_size))?;
+            self.read_device(first * sector_size, &mut covered[from..to])?;
+
+            if let Some(cache) = &mut self.cache {
+                for (sector, data) in
+                    (first..=last).zip(covered[from..to].chunks_exact(sector_size as usize))
+                {
+                    cache.insert(sector, data);
+                }
+            }
+        } else {
+            cache.hits += 1;
+        }
+
+        let from = (start - sectors.start * sector_size) as usize;
+        buf.copy_from_slice(&covered[from..from + buf.len()]);
+        self.vexfat.seek(SeekFrom::Start(end)).map(|_| ())
+    }
+
+    /// Reads `buf` at `start`, the device is positioned there. Served from the read-ahead if it
+    /// holds the whole range, sequential reads that miss it fetch the sectors after `buf` too.
+    fn read_device(&mut self, start: u64, buf: &mut [u8]) -> io::Result<()> {
+        let end = start + buf.len() as u64;
+        let sequential = self.last_read_end == Some(start);
+        self.last_read_end = Some(end);
+
+        if let Some(read_ahead) = &self.read_ahead {
+            if start >= read_ahead.offset && end <= read_ahead.offset + read_ahead.data.len() as u64
+            {
+                let from = (start - read_ahead.offset) as usize;
+                buf.copy_from_slice(&read_ahead.data[from..from + buf.len()]);
+                return self.vexfat.seek(SeekFrom::Start(end)).map(|_| ());
+            }
+        }
+        self.read_ahead = None;
+
+        let volume_size = u64::from(self.sector_count) * u64::from(self.sector_size());
+        let ahead = (u64::from(self.read_ahead_sectors) * u64::from(self.sector_size()))
+            .min(volume_size.saturating_sub(end));
+        if ahead == 0 || !sequential {
+            return self.vexfat.read_exact(buf);
+        }
+
+        let mut data = vec![0; buf.len() + ahead as usize];
+        if self.vexfat.read_exact(&mut data).is_err() {
+            // the extra sectors may be what failed, retry with just what was asked for
+            self.vexfat.seek(SeekFrom::Start(start))?;
+            return self.vexfat.read_exact(buf);
+        }
+
+        buf.copy_from_slice(&data[..buf.len()]);
+        self.read_ahead = Some(ReadAhead {
+            offset: end,
+            data: data.split_off(buf.len()),
+        });
+        self.vexfat.seek(SeekFrom::Start(end)).map(|_| ())
     }
+
+    /// Drops data read ahead, it may no longer match the volume.
+    fn forget_read_ahead(&mut self) {
+        self.read_ahead = None;
+        self.last_read_end = None;
+    }
+
+    /// Like `read`, but the part of `buf` past the end of the volume is zero-filled instead of
+    /// failing the whole read.
+    pub fn read_padded(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let start = self.vexfat.stream_position()?;
+        let volume_size = u64::from(self.sector_count) * u64::from(self.sector_size());
+        let inside = volume_size.saturating_sub(start).min(buf.len() as u64) as usize;
+
+        let (data, tail) = buf.split_at_mut(inside);
+        tail.fill(0);
+        let result = if data.is_empty() {
+            Ok(())
+        } else {
+            self.read(data)
+        };
+
+        // past the tail even if the data failed, like `read`
+        if !tail.is_empty() {
+            self.vexfat
+                .seek(SeekFrom::Start(start + buf.len() as u64))?;
+        }
+        result
+    }
+
+    /// Reread the range just read into `buf` and check that it still matches.
+    pub fn verify(&mut self, buf: &[u8]) -> io::Result<bool> {
+        let end = self.vexfat.stream_position()?;
+        self.vexfat.seek(SeekFrom::Start(end - buf.len() as u64))?;
+        // read from the device again rather than from memory, without reading ahead or making
+        // the next read look any less sequential than it is
+        let read_ahead = self.read_ahead.take();
+        let last_read_end = self.last_read_end.take();
+        let cache = self.cache.take();
+
+        let mut reread = vec![0; buf.len()];
+        let result = self.read_padded(&mut reread);
+        self.read_ahead = read_ahead;
+        self.last_read_end = last_read_end;
+        self.cache = cache;
+        result?;
+
+        Ok(reread == buf)
+    }
+
+    /// Writes `buf` at the current position, to the overlay if there is one, otherwise through to
+    /// the image or the mapped files backing it. Without an overlay only file data of a mapped
+    /// directory is writable, writes touching the boot region, FAT or directories fail. Like
+    /// `read`, the position always advances by `buf.len()`.
+    pub fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        let start = self.vexfat.stream_position()?;
+        self.forget_read_ahead();
+        if let Some(cache) = &mut self.cache {
+            let sector_size = u64::from(self.vexfat.bytes_per_sector());
+            cache.remove(start / sector_size..(start + buf.len() as u64).div_ceil(sector_size));
+        }
+
+        if let Some(overlay) = &mut self.overlay {
+            let vexfat = &mut self.vexfat;
+            let result = overlay.write(start, buf, |offset, sector| {
+                vexfat.seek(SeekFrom::Start(offset))?;
+                vexfat.read_exact(sector)
+            });
+
+            self.vexfat
+                .seek(SeekFrom::Start(start + buf.len() as u64))?;
+            return result;
+        }
+
+        if let Device::Image { file, .. } = &mut self.vexfat {
+            let result = file.write_all(buf);
+
+            self.vexfat
+                .seek(SeekFrom::Start(start + buf.len() as u64))?;
+            return result;
+        }
+
+        // the device would write into the placeholder, where the data is never read from
+        let result = if self.touches_memory_files(start, buf.len()) {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "files served from memory are only writable with an overlay",
+            ))
+        } else {
+            self.write_at(start, buf)
+        };
+
+        self.vexfat
+            .seek(SeekFrom::Start(start + buf.len() as u64))?;
+        result
+    }
+
+    fn write_at(&mut self, mut offset: u64, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let extent = self.file_at(offset).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("byte {offset} of the volume isn't part of a mapped file"),
+                )
+            })?;
+
+            let within = offset - extent.volume_offset;
+            let len = buf.len().min((extent.len - within) as usize);
+
+            // anything past the end of the file is cluster slack, drop it
+            let data_len = len.min(extent.data_len.saturating_sub(within) as usize);
+            if data_len > 0 {
+                let position = extent.file_offset + within;
+                let reopen = !matches!(&self.written_file, Some((path, _)) if *path == extent.path);
+                if reopen {
+                    let path = extent.path.clone();
+                    let file = fs::OpenOptions::new()
+                        .write(true)
+                        .open(&self.files[&path])?;
+                    self.written_file = Some((path, file));
+                }
+
+                let (_, file) = self.written_file.as_mut().unwrap();
+                file.seek(SeekFrom::Start(position))?;
+                file.write_all(&buf[..data_len])?;
+            }
+
+            offset += len as u64;
+            buf = &buf[len..];
+        }
+
+        Ok(())
+    }
+
+    /// Whether the `len` bytes at `start` overlap the data of a file served from memory.
+    fn touches_memory_files(&self, start: u64, len: usize) -> bool {
+        self.memory_runs
+            .range(..start + len as u64)
+            .next_back()
+            .is_some_and(|(&offset, run)| offset + run.len() as u64 > start)
+    }
+
+    /// Run of mapped file data holding byte `offset` of the volume.
+    pub fn file_at(&self, offset: u64) -> Option<&Extent> {
+        let index = self
+            .extents
+            .partition_point(|extent| extent.volume_offset + extent.len <= offset);
+
+        self.extents
+            .get(index)
+            .filter(|extent| extent.volume_offset <= offset)
+    }
+
+    /// Size of the file at `path` on the volume when it was mapped.
+    pub fn mapped_size(&self, path: &str) -> u64 {
+        self.extents
+            .iter()
+            .filter(|extent| extent.path == path)
+            .map(|extent| extent.data_len)
+            .sum()
+    }
+
+    /// Hits and misses of the sector cache, `None` if it's disabled.
+    pub fn cache_stats(&self) -> Option<(u64, u64)> {
+        self.cache.as_ref().map(|cache| (cache.hits, cache.misses))
+    }
+
+    pub fn sector_size(&self) -> u16 {
+        self.vexfat.bytes_per_sector()
+    }
+
+    pub fn sector_count(&self) -> u32 {
+        self.sector_count
+    }
+
+    /// Host file mapped at `path` on the volume, as returned by [`exfat::read_tree`].
+    pub fn host_path(&self, path: &str) -> Option<&Path> {
+        self.files.get(path).map(PathBuf::as_path)
+    }
+
+    /// Game in the image mapped at `path` on the volume, if titles were read and it has one.
+    pub fn title(&self, path: &str) -> Option<&iso::Title> {
+        self.titles.get(path)
+    }
+}
+
+#[cfg(test)]
+pub fn test_vexfat(root: &Path, configure: impl FnOnce(&mut VexFatConfig)) -> VexFat {
+    let mut config = VexFatConfig::new(root);
+    configure(&mut config);
+
+    VexFat::new(&config).unwrap()
+}
+
+/// Paths on the volume of everything mapped from `root`, with the config adjusted by `configure`.
+#[cfg(test)]
+fn mapped_paths(root: &Path, configure: impl FnOnce(&mut VexFatConfig)) -> Vec<String> {
+    let mut vexfat = test_vexfat(root, configure);
+    let tree = exfat::read_tree(&mut vexfat).unwrap();
+    tree.into_iter().map(|(path, _)| path).collect()
+}
+
+#[test]
+fn boot_sector() {
+    let root = crate::utils::test_dir("boot-sector");
+    fs::write(root.join("file.bin"), [0xAA; 4096]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let mut sector = vec![0; usize::from(vexfat.sector_size())];
+    vexfat.seek(0).unwrap();
+    vexfat.read(&mut sector).unwrap();
+
+    assert_eq!(&sector[3..11], b"EXFAT   ");
+    assert_eq!(sector[510..512], [0x55, 0xAA]);
+
+    let boot = crate::exfat::BootSector::read(&mut vexfat).unwrap();
+    assert_eq!(boot.volume_length, u64::from(vexfat.sector_count()));
+    assert_eq!(
+        boot.sectors_per_cluster_shift,
+        DEFAULT_SECTORS_PER_CLUSTER_SHIFT
+    );
+
+    let mut vexfat = VexFat::new(&VexFatConfig::new(&root).sectors_per_cluster_shift(4)).unwrap();
+    let boot = crate::exfat::BootSector::read(&mut vexfat).unwrap();
+    assert_eq!(boot.sectors_per_cluster_shift, 4);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn root_must_be_a_directory() {
+    let root = crate::utils::test_dir("root-not-a-directory");
+    let file = root.join("file.bin");
+    fs::write(&file, [0; 512]).unwrap();
+
+    let err = VexFat::new(&VexFatConfig::new(&file)).err().unwrap();
+    assert!(err.to_string().contains("is not a directory"));
+    let err = VexFat::new(&VexFatConfig::new(root.join("missing")))
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("does not exist"));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn file_at_offset() {
+    let root = crate::utils::test_dir("file-at-offset");
+    fs::write(root.join("a.bin"), [1; 512]).unwrap();
+    fs::write(root.join("b.bin"), [2; 512]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let boot = exfat::BootSector::read(&mut vexfat).unwrap();
+    let sector_size = u64::from(vexfat.sector_size());
+    for (path, entry) in exfat::read_tree(&mut vexfat).unwrap() {
+        if entry.is_directory || entry.size == 0 {
+            continue;
+        }
+        let offset = u64::from(boot.cluster_sector(entry.first_cluster)) * sector_size;
+        assert_eq!(vexfat.file_at(offset).unwrap().path, path);
+        // anywhere in the run, slack included
+        assert_eq!(vexfat.file_at(offset + 1000).unwrap().path, path);
+    }
+    assert!(vexfat.file_at(0).is_none());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn no_create_dirs() {
+    let root = crate::utils::test_dir("no-create-dirs");
+    fs::write(root.join("file.bin"), [0; 512]).unwrap();
+
+    test_vexfat(&root, |config| config.create_dirs = false);
+    assert!(!root.join("DVD").exists());
+    test_vexfat(&root, |_| {});
+    assert!(root.join("DVD").is_dir());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn dry_run_leaves_root_untouched() {
+    let root = crate::utils::test_dir("dry-run");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("DVD").join("game.iso"), [1; 512]).unwrap();
+
+    let mut config = VexFatConfig::new(&root);
+    config.overlay = Some(root.join("overlay"));
+    let missing = config.dry_run();
+    assert_eq!(missing.len(), OPL_DIRS.len() - 1);
+    assert!(!missing.contains(&root.join("DVD")));
+
+    let mut vexfat = VexFat::new(&config).unwrap();
+    let tree = exfat::read_tree(&mut vexfat).unwrap();
+    assert!(tree.iter().any(|(path, _)| path == "DVD/game.iso"));
+    let entries: Vec<_> = fs::read_dir(&root)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(entries, ["DVD"]);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn follow_symlinks() {
+    use std::os::unix::fs::symlink;
+
+    let root = crate::utils::test_dir("follow-symlinks");
+    let library = crate::utils::test_dir("follow-symlinks-library");
+    fs::write(library.join("game.iso"), [1; 4096]).unwrap();
+    symlink(&library, root.join("DVD")).unwrap();
+    symlink(root.join("missing.iso"), root.join("broken.iso")).unwrap();
+    // a cycle back to root
+    fs::create_dir(root.join("CD")).unwrap();
+    symlink(&root, root.join("CD").join("loop")).unwrap();
+
+    let skipped = mapped_paths(&root, |config| config.create_dirs = false);
+    assert!(!skipped.iter().any(|path| path.starts_with("DVD")));
+
+    let mut vexfat = test_vexfat(&root, |config| {
+        config.create_dirs = false;
+        config.follow_symlinks = true;
+    });
+    let followed = exfat::read_tree(&mut vexfat).unwrap();
+    assert!(followed
+        .iter()
+        .any(|(path, entry)| path == "DVD/game.iso" && entry.size == 4096));
+    assert!(!followed.iter().any(|(path, _)| path.contains("broken")));
+    assert!(!followed.iter().any(|(path, _)| path.contains("loop")));
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(library).unwrap();
+}
+
+#[test]
+fn read_past_4gib() {
+    use std::io::Write;
+
+    let root = crate::utils::test_dir("read-past-4gib");
+    let past_4gib: u64 = 9 << 29; // 4.5 GiB
+
+    let mut file = fs::File::create(root.join("big.iso")).unwrap();
+    file.set_len(5 << 30).unwrap(); // sparse
+    file.write_all(b"first sector").unwrap();
+    file.seek(SeekFrom::Start(past_4gib)).unwrap();
+    file.write_all(b"past 4 GiB").unwrap();
+    drop(file);
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let boot = crate::exfat::BootSector::read(&mut vexfat).unwrap();
+    let sector_size = u64::from(vexfat.sector_size());
+    let mut sector = vec![0; usize::from(vexfat.sector_size())];
+
+    // the file is the only one with data, find the cluster it starts at
+    let start = (2..64)
+        .map(|cluster| boot.cluster_sector(cluster))
+        .find(|&start| {
+            vexfat.seek(start).unwrap();
+            vexfat.read(&mut sector).unwrap();
+            sector.starts_with(b"first sector")
+        })
+        .unwrap();
+
+    let sector_nr = start + u32::try_from(past_4gib / sector_size).unwrap();
+    vexfat.seek(sector_nr).unwrap();
+    vexfat.read(&mut sector).unwrap();
+    assert!(sector.starts_with(b"past 4 GiB"));
+
+    vexfat.seek(vexfat.sector_count() - 1).unwrap();
+    vexfat.read(&mut sector).unwrap();
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn prefix_placement() {
+    let root = crate::utils::test_dir("prefix-placement");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("top.bin"), [1; 512]).unwrap();
+    fs::write(root.join("DVD").join("game.iso"), [2; 512]).unwrap();
+
+    let unprefixed = mapped_paths(&root, |_| {});
+    assert!(unprefixed.contains(&String::from("top.bin")));
+    assert!(unprefixed.contains(&String::from("DVD/game.iso")));
+
+    let prefixed = mapped_paths(&root, |config| config.prefix = Some(String::from("OPL")));
+    assert!(prefixed.contains(&String::from("OPL/top.bin")));
+    assert!(prefixed.contains(&String::from("OPL/DVD/game.iso")));
+    assert!(!prefixed.contains(&String::from("top.bin")));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn sidecar_files_skipped() {
+    let root = crate::utils::test_dir("sidecar-files");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("DVD").join("game.iso"), [1; 512]).unwrap();
+    fs::write(root.join("DVD").join("._game.iso"), [2; 512]).unwrap();
+    fs::write(root.join(".DS_Store"), [3; 512]).unwrap();
+
+    let skipped = mapped_paths(&root, |_| {});
+    assert!(skipped.contains(&String::from("DVD/game.iso")));
+    assert!(!skipped.contains(&String::from("DVD/._game.iso")));
+    assert!(!skipped.contains(&String::from(".DS_Store")));
+
+    let kept = mapped_paths(&root, |config| config.keep_sidecar_files = true);
+    assert!(kept.contains(&String::from("DVD/._game.iso")));
+    assert!(kept.contains(&String::from(".DS_Store")));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn invalid_names() {
+    let root = crate::utils::test_dir("invalid-names");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::create_dir(root.join("CD")).unwrap();
+    fs::write(root.join("DVD").join("bad:name.iso"), [1; 512]).unwrap();
+    fs::write(root.join("DVD").join("good.iso"), [2; 512]).unwrap();
+    fs::create_dir(root.join("CD").join("Disc 1.")).unwrap();
+    fs::write(root.join("CD").join("Disc 1.").join("game.iso"), [3; 512]).unwrap();
+
+    let skipped = mapped_paths(&root, |_| {});
+    assert!(skipped.contains(&String::from("DVD/good.iso")));
+    assert!(!skipped.iter().any(|path| path.contains("bad")));
+    assert!(!skipped.iter().any(|path| path.contains("Disc 1")));
+
+    let renamed = mapped_paths(&root, |config| config.invalid_names = InvalidNames::Rename);
+    assert!(renamed.contains(&String::from("CD/Disc 1/game.iso")));
+    assert!(renamed.contains(&String::from("DVD/bad_name.iso")));
+    assert!(!renamed.iter().any(|path| path.contains("bad:name")));
+
+    assert_eq!(sanitize_name("a<b>?.. "), "a_b__");
+    assert_eq!(sanitize_name("..."), "_");
+    assert_eq!(sanitize_name(&"x".repeat(300)).len(), MAX_NAME_LEN);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn case_insensitive_duplicates() {
+    let root = crate::utils::test_dir("case-duplicates");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("DVD").join("Game.iso"), [1; 512]).unwrap();
+    fs::write(root.join("DVD").join("game.iso"), [2; 512]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let tree = crate::exfat::read_tree(&mut vexfat).unwrap();
+    let mapped = tree
+        .iter()
+        .filter(|(path, _)| path.eq_ignore_ascii_case("DVD/game.iso"))
+        .count();
+    assert_eq!(mapped, 1);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn failed_read_keeps_alignment() {
+    let root = crate::utils::test_dir("failed-read-alignment");
+    fs::write(root.join("file.bin"), [0xAA; 4096]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let volume_size = vexfat.vexfat.volume_size();
+
+    // a read running off the end of the volume fails after reading part of the buffer
+    let start = volume_size - 256;
+    vexfat.seek_offset(start).unwrap();
+    assert!(vexfat.read(&mut [0; 512]).is_err());
+    assert_eq!(vexfat.vexfat.stream_position().unwrap(), start + 512);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn shard_large_directories() {
+    let root = crate::utils::test_dir("shard-directories");
+    fs::create_dir(root.join("DVD")).unwrap();
+    for name in ["aaa.iso", "foo.iso", "zed.iso"] {
+        fs::write(root.join("DVD").join(name), [1; 512]).unwrap();
+    }
+    fs::create_dir(root.join("CD")).unwrap();
+    fs::write(root.join("CD").join("bar.iso"), [2; 512]).unwrap();
+
+    let paths = mapped_paths(&root, |config| config.shard_over = Some(2));
+
+    assert!(paths.contains(&String::from("DVD/A-E/aaa.iso")));
+    assert!(paths.contains(&String::from("DVD/F-J/foo.iso")));
+    assert!(paths.contains(&String::from("DVD/U-Z/zed.iso")));
+    assert!(!paths.contains(&String::from("DVD/aaa.iso")));
+    // directories under the limit are left alone
+    assert!(paths.contains(&String::from("CD/bar.iso")));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn volume_fits_files() {
+    let root = crate::utils::test_dir("volume-fits-files");
+    let sizes: [u64; 2] = [3 << 20, 5 << 20];
+    for (n, size) in sizes.iter().enumerate() {
+        let file = fs::File::create(root.join(format!("file{n}.bin"))).unwrap();
+        file.set_len(*size).unwrap();
+    }
+
+    let vexfat = test_vexfat(&root, |_| {});
+    let volume_bytes = u64::from(vexfat.sector_count()) * u64::from(vexfat.sector_size());
+    assert!(volume_bytes >= sizes.iter().sum());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn write_through_to_file() {
+    let root = crate::utils::test_dir("write-through");
+    fs::create_dir_all(root.join("CFG")).unwrap();
+    fs::write(root.join("CFG").join("game.cfg"), [0; 1000]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let boot = exfat::BootSector::read(&mut vexfat).unwrap();
+    let tree = exfat::read_tree(&mut vexfat).unwrap();
+    let (_, entry) = tree
+        .iter()
+        .find(|(path, _)| path == "CFG/game.cfg")
+        .unwrap();
+
+    // the second sector is only partly file data, the rest is dropped
+    let mut data = vec![0xAB; 1024];
+    data[..5].copy_from_slice(b"hello");
+    vexfat
+        .seek(boot.cluster_sector(entry.first_cluster))
+        .unwrap();
+    vexfat.write(&data).unwrap();
+    // the file stays open for the next packet of the write
+    assert_eq!(vexfat.written_file.as_ref().unwrap().0, "CFG/game.cfg");
+
+    let written = fs::read(root.join("CFG").join("game.cfg")).unwrap();
+    assert_eq!(written.len(), 1000);
+    assert!(written.starts_with(b"hello"));
+    assert!(written[5..].iter().all(|&byte| byte == 0xAB));
+
+    // the boot sector isn't backed by a file
+    vexfat.seek(0).unwrap();
+    assert!(vexfat.write(&[0; 512]).is_err());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn overlay_shadows_files() {
+    let root = crate::utils::test_dir("overlay-shadows");
+    let overlay = crate::utils::test_dir("overlay-shadows-store");
+    fs::write(root.join("file.bin"), [0xAA; 4096]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |config| config.overlay = Some(overlay.clone()));
+    // the boot sector can be written too, nothing touches the mapped files
+    vexfat.seek(0).unwrap();
+    vexfat.write(&[0x55; 16]).unwrap();
+    drop(vexfat);
+
+    let mut vexfat = test_vexfat(&root, |config| config.overlay = Some(overlay.clone()));
+    let mut sector = [0; 512];
+    vexfat.seek(0).unwrap();
+    vexfat.read(&mut sector).unwrap();
+    assert_eq!(sector[..16], [0x55; 16]);
+    // the rest of the sector still comes from the volume
+    assert_eq!(&sector[510..], [0x55, 0xAA]);
+    assert_eq!(fs::read(root.join("file.bin")).unwrap(), [0xAA; 4096]);
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(overlay).unwrap();
+}
+
+#[test]
+fn overlay_survives_a_layout_change() {
+    let root = crate::utils::test_dir("overlay-layout");
+    let overlay = crate::utils::test_dir("overlay-layout-store");
+    fs::write(root.join("file.bin"), [0xAA; 4096]).unwrap();
+    let configure = |config: &mut VexFatConfig| config.overlay = Some(overlay.clone());
+
+    let mut vexfat = test_vexfat(&root, configure);
+    vexfat.seek(0).unwrap();
+    vexfat.write(&[0x55; 16]).unwrap();
+    let sectors = vexfat.sector_count();
+    drop(vexfat);
+
+    // a new game grows the volume, the writes don't apply to it but are kept
+    fs::write(root.join("DVD").join("game.iso"), vec![0xBB; 4 << 20]).unwrap();
+    let mut vexfat = test_vexfat(&root, configure);
+    assert!(vexfat.sector_count() > sectors);
+    let mut sector = [0; 512];
+    vexfat.seek(0).unwrap();
+    vexfat.read(&mut sector).unwrap();
+    assert_ne!(sector[..16], [0x55; 16]);
+    drop(vexfat);
+
+    // and are back once the volume is laid out as they were written for
+    fs::remove_file(root.join("DVD").join("game.iso")).unwrap();
+    let mut vexfat = test_vexfat(&root, configure);
+    vexfat.seek(0).unwrap();
+    vexfat.read(&mut sector).unwrap();
+    assert_eq!(sector[..16], [0x55; 16]);
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(overlay).unwrap();
+}
+
+#[test]
+fn serve_image() {
+    let root = crate::utils::test_dir("serve-image");
+    let image = root.join("volume.img");
+    fs::write(&image, [0xAB; 64 * 512]).unwrap();
+
+    let mut config = VexFatConfig::new(&image);
+    config.image = true;
+    let mut vexfat = VexFat::new(&config).unwrap();
+    assert_eq!(vexfat.sector_count(), 64);
+
+    vexfat.seek(1).unwrap();
+    vexfat.write(&[0xCD; 512]).unwrap();
+
+    let mut sector = [0; 512];
+    vexfat.seek(1).unwrap();
+    vexfat.read(&mut sector).unwrap();
+    assert_eq!(sector, [0xCD; 512]);
+    assert_eq!(fs::read(&image).unwrap()[512..1024], [0xCD; 512]);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn read_ahead_sequential_reads() {
+    let root = crate::utils::test_dir("read-ahead");
+    let image = root.join("volume.img");
+    let sectors: Vec<u8> = (0..64u8).flat_map(|sector| [sector; 512]).collect();
+    fs::write(&image, sectors).unwrap();
+
+    let mut config = VexFatConfig::new(&image);
+    config.image = true;
+    let mut vexfat = VexFat::new(&config).unwrap();
+    let mut sector = [0; 512];
+
+    vexfat.seek(0).unwrap();
+    vexfat.read(&mut sector).unwrap();
+    assert!(vexfat.read_ahead.is_none());
+    vexfat.read(&mut sector).unwrap();
+    assert_eq!(sector, [1; 512]);
+    assert!(vexfat.read_ahead.is_some());
+    vexfat.read(&mut sector).unwrap();
+    assert_eq!(sector, [2; 512]);
+
+    // verifying rereads only what was read and keeps the sectors read ahead for the next read
+    assert!(vexfat.verify(&sector).unwrap());
+    assert!(vexfat.read_ahead.is_some());
+    assert_eq!(vexfat.last_read_end, Some(3 * 512));
+
+    // writes drop the sectors read ahead
+    vexfat.write(&[0xFF; 512]).unwrap();
+    assert!(vexfat.read_ahead.is_none());
+    vexfat.seek(3).unwrap();
+    vexfat.read(&mut sector).unwrap();
+    assert_eq!(sector, [0xFF; 512]);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn sector_cache() {
+    let root = crate::utils::test_dir("sector-cache");
+    let image = root.join("volume.img");
+    fs::write(&image, [0xAB; 64 * 512]).unwrap();
+
+    let mut config = VexFatConfig::new(&image);
+    config.image = true;
+    config.read_ahead_sectors = 0;
+    config.cache_size = 8 * 512;
+    let mut vexfat = VexFat::new(&config).unwrap();
+    let mut data = [0; 300];
+
+    // partial sectors are cached whole
+    vexfat.seek_offset(5 * 512 + 400).unwrap();
+    vexfat.read(&mut data).unwrap();
+    vexfat.seek_offset(5 * 512 + 400).unwrap();
+    vexfat.read(&mut data).unwrap();
+    assert_eq!(vexfat.cache_stats(), Some((1, 1)));
+
+    vexfat.seek(6).unwrap();
+    vexfat.write(&[0xCD; 512]).unwrap();
+    vexfat.seek_offset(5 * 512 + 400).unwrap();
+    vexfat.read(&mut data).unwrap();
+    assert_eq!(vexfat.cache_stats(), Some((1, 2)));
+    assert_eq!(data[..112], [0xAB; 112]);
+    assert_eq!(data[112..], [0xCD; 188]);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn rescan_picks_up_new_files() {
+    let root = crate::utils::test_dir("rescan");
+    fs::write(root.join("old.bin"), [1; 512]).unwrap();
+    let config = VexFatConfig::new(&root);
+
+    let mut vexfat = VexFat::new(&config).unwrap();
+    fs::write(root.join("new.bin"), [2; 512]).unwrap();
+    vexfat.rescan(&config).unwrap();
+
+    let tree = crate::exfat::read_tree(&mut vexfat).unwrap();
+    assert!(tree.iter().any(|(path, _)| path == "old.bin"));
+    assert!(tree.iter().any(|(path, _)| path == "new.bin"));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn volume_size_limits() {
+    assert_eq!(check_volume_sectors(1 << 20, 512).unwrap(), 1 << 20);
+    // past the safe size only warns
+    assert_eq!(
+        check_volume_sectors(SAFE_VOLUME_SECTORS + 1, 512).unwrap(),
+        (SAFE_VOLUME_SECTORS + 1) as u32
+    );
+    assert!(check_volume_sectors(u64::from(u32::MAX) + 1, 512).is_err());
+}
+
+#[test]
+fn cluster_count_floor() {
+    let bytes_per_cluster = 1 << (DEFAULT_BYTES_PER_SECTOR_SHIFT + 11);
+
+    let empty = cluster_count(0, 0, 0, bytes_per_cluster);
+    assert!(empty >= MIN_CLUSTER_COUNT);
+    assert_eq!(empty % 2, 0);
+
+    assert_eq!(
+        cluster_count(bytes_per_cluster * 100, 1, 1, bytes_per_cluster),
+        106
+    );
+}
+
+#[test]
+fn read_past_end_is_zero_filled() {
+    let root = crate::utils::test_dir("read-past-end");
+    fs::write(root.join("file.bin"), [0xAA; 4096]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let sector_size = usize::from(vexfat.sector_size());
+    let last_sector = vexfat.sector_count() - 1;
+
+    let mut expected = vec![0; sector_size];
+    vexfat.seek(last_sector).unwrap();
+    vexfat.read(&mut expected).unwrap();
+    expected.resize(sector_size * 2, 0);
+
+    // the last sector plus one past the end
+    let mut buf = vec![0xFF; sector_size * 2];
+    vexfat.seek(last_sector).unwrap();
+    vexfat.read_padded(&mut buf).unwrap();
+    assert_eq!(buf, expected);
+
+    // entirely past the end
+    vexfat.seek(last_sector + 1).unwrap();
+    vexfat.read_padded(&mut buf).unwrap();
+    assert!(buf.iter().all(|&byte| byte == 0));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn exclude_and_include_globs() {
+    let root = crate::utils::test_dir("exclude-globs");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("DVD/game.iso"), [1; 512]).unwrap();
+    fs::write(root.join("DVD/readme.txt"), [2; 512]).unwrap();
+    fs::write(root.join("DVD/Thumbs.db"), [3; 512]).unwrap();
+
+    let files = |configure: fn(&mut VexFatConfig)| {
+        let mut paths = mapped_paths(&root, configure);
+        paths.retain(|path| path.starts_with("DVD/"));
+        paths.sort();
+        paths
+    };
+
+    assert_eq!(
+        files(|config| {
+            config.exclude = vec![String::from("*.txt"), String::from("Thumbs.db")];
+        }),
+        ["DVD/game.iso"]
+    );
+    assert_eq!(
+        files(|config| config.include = vec![String::from("*.iso")]),
+        ["DVD/game.iso"]
+    );
+    assert_eq!(files(|_| {}).len(), 3);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn sort_by_size() {
+    let root = crate::utils::test_dir("sort-by-size");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("DVD/a.iso"), [1; 512]).unwrap();
+    fs::write(root.join("DVD/b.iso"), [2; 4096]).unwrap();
+    fs::write(root.join("DVD/c.iso"), [3; 2048]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |config| config.sort_by = SortBy::Size);
+    let listed: Vec<_> = exfat::read_tree(&mut vexfat)
+        .unwrap()
+        .into_iter()
+        .map(|(path, _)| path)
+        .filter(|path| path.starts_with("DVD/"))
+        .collect();
+    assert_eq!(listed, ["DVD/b.iso", "DVD/c.iso", "DVD/a.iso"]);
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn name_order_is_platform_independent() {
+    let mut names = vec![
+        OsStr::new("z.iso"),
+        // decomposed, as macOS stores it, sorts the same as the composed form
+        OsStr::new("e\u{301}cole.iso"),
+        OsStr::new("Zelda.iso"),
+        OsStr::new("\u{e9}t\u{e9}.iso"),
+        OsStr::new("a.iso"),
+    ];
+    names.sort_by(|a, b| compare_names(a, b));
+
+    assert_eq!(
+        names,
+        [
+            "Zelda.iso",
+            "a.iso",
+            "z.iso",
+            "e\u{301}cole.iso",
+            "\u{e9}t\u{e9}.iso"
+        ]
+    );
+}
+
+#[test]
+fn merge_roots() {
+    let ps2 = crate::utils::test_dir("merge-roots-ps2");
+    let ps1 = crate::utils::test_dir("merge-roots-ps1");
+    fs::create_dir(ps2.join("DVD")).unwrap();
+    fs::write(ps2.join("DVD/game.iso"), [1; 512]).unwrap();
+    fs::write(ps2.join("DVD/both.iso"), [2; 512]).unwrap();
+    fs::create_dir(ps1.join("DVD")).unwrap();
+    fs::write(ps1.join("DVD/both.iso"), [3; 1024]).unwrap();
+    fs::create_dir(ps1.join("POPS")).unwrap();
+    fs::write(ps1.join("POPS/game.vcd"), [4; 512]).unwrap();
+
+    let mut vexfat = test_vexfat(&ps2, |config| config.extra_roots = vec![ps1.clone()]);
+    let tree = crate::exfat::read_tree(&mut vexfat).unwrap();
+    let paths: Vec<_> = tree.iter().map(|(path, _)| path.as_str()).collect();
+
+    // the DVD directories are combined, a name in both roots is mapped from the first
+    assert_eq!(paths.iter().filter(|path| **path == "DVD").count(), 1);
+    assert!(paths.contains(&"DVD/game.iso"));
+    assert!(paths.contains(&"POPS/game.vcd"));
+    let (_, both) = tree
+        .iter()
+        .find(|(path, _)| path == "DVD/both.iso")
+        .unwrap();
+    assert_eq!(both.size, 512);
+
+    fs::remove_dir_all(ps2).unwrap();
+    fs::remove_dir_all(ps1).unwrap();
+}
+
+#[test]
+fn games_under_cd_and_dvd() {
+    let game = |path: &str| game_of(Path::new(path));
+
+    assert_eq!(game("DVD/game.iso"), Some(PathBuf::from("DVD/game.iso")));
+    assert_eq!(game("CD/game/game.00"), Some(PathBuf::from("CD/game")));
+    assert_eq!(game("dvd/game.iso"), Some(PathBuf::from("dvd/game.iso")));
+    assert_eq!(game("DVD/A/game.iso"), Some(PathBuf::from("DVD/A")));
+    assert_eq!(game("ART/game_COV.png"), None);
+    assert_eq!(game("DVD"), None);
+}
+
+#[test]
+fn staged_images_by_size() {
+    let root = crate::utils::test_dir("staged-images-root");
+    let staging = crate::utils::test_dir("staged-images-staging");
+    fs::write(staging.join("small.iso"), [1; 512]).unwrap();
+    fs::File::create(staging.join("large.iso"))
+        .unwrap()
+        .set_len(CD_IMAGE_MAX_SIZE + 2048)
+        .unwrap();
+    fs::write(staging.join("notes.txt"), "not an image").unwrap();
+
+    let mut vexfat = test_vexfat(&root, |config| config.staging = Some(staging.clone()));
+    let tree = crate::exfat::read_tree(&mut vexfat).unwrap();
+    let paths: Vec<_> = tree.iter().map(|(path, _)| path.as_str()).collect();
+
+    assert!(paths.contains(&"CD/small.iso"));
+    assert!(paths.contains(&"DVD/large.iso"));
+    assert!(!paths.iter().any(|path| path.contains("notes")));
+
+    fs::remove_dir_all(root).unwrap();
+    fs::remove_dir_all(staging).unwrap();
+}
+
+#[test]
+fn split_games_get_ul_cfg() {
+    let root = crate::utils::test_dir("split-games");
+    fs::write(root.join("ul.00000001.SLUS_000.01.00"), [1; 512]).unwrap();
+    fs::write(root.join("ul.00000001.SLUS_000.01.01"), [2; 512]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |_| {});
+    let tree = crate::exfat::read_tree(&mut vexfat).unwrap();
+    let paths: Vec<_> = tree.iter().map(|(path, _)| path.as_str()).collect();
+
+    // without an image to read the name from, the game is listed under its serial
+    let crc = ul::crc32("SLUS_000.01");
+    assert!(paths.contains(&"ul.cfg"));
+    assert!(paths.contains(&format!("ul.{crc:08X}.SLUS_000.01.00").as_str()));
+    assert!(paths.contains(&format!("ul.{crc:08X}.SLUS_000.01.01").as_str()));
+    assert!(!paths.contains(&"ul.00000001.SLUS_000.01.00"));
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn memory_files() {
+    let root = crate::utils::test_dir("memory-files");
+    let readme: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+
+    let mut vexfat = test_vexfat(&root, |config| {
+        config.memory_files = vec![
+            (String::from("README.txt"), readme.clone()),
+            (String::from("CFG/server.cfg"), b"served=1\n".to_vec()),
+        ]
+    });
+    let extents = crate::exfat::file_extents(&mut vexfat).unwrap();
+
+    let read_file = |vexfat: &mut VexFat, path: &str| {
+        let mut data = Vec::new();
+        for extent in extents.iter().filter(|extent| extent.path == path) {
+            let mut buf = vec![0; extent.data_len as usize];
+            vexfat.seek_offset(extent.volume_offset).unwrap();
+            vexfat.read(&mut buf).unwrap();
+            data.extend(buf);
+        }
+        data
+    };
+
+    assert_eq!(read_file(&mut vexfat, "README.txt"), readme);
+    assert_eq!(read_file(&mut vexfat, "CFG/server.cfg"), b"served=1\n");
+
+    // without an overlay the write would only reach the placeholder
+    let extent = extents
+        .iter()
+        .find(|extent| extent.path == "README.txt")
+        .unwrap();
+    vexfat.seek_offset(extent.volume_offset + 512).unwrap();
+    let err = vexfat.write(&[0; 512]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert_eq!(read_file(&mut vexfat, "README.txt"), readme);
+
+    // the placeholders go with the volume
+    let scratch = vexfat.scratch.0.clone();
+    assert!(scratch.exists());
+    drop(vexfat);
+    assert!(!scratch.exists());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn names_are_composed() {
+    let composed = crate::utils::test_dir("names-composed");
+    let decomposed = crate::utils::test_dir("names-decomposed");
+    for (root, name) in [(&composed, "Caf\u{e9}"), (&decomposed, "Cafe\u{301}")] {
+        fs::create_dir(root.join("DVD")).unwrap();
+        fs::create_dir(root.join("DVD").join(name)).unwrap();
+        fs::write(
+            root.join("DVD").join(name).join(format!("{name}.iso")),
+            [1; 512],
+        )
+        .unwrap();
+    }
+
+    let tree = |root: &Path| -> Vec<String> {
+        let mut vexfat = test_vexfat(root, |_| {});
+        crate::exfat::read_tree(&mut vexfat)
+            .unwrap()
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect()
+    };
+
+    let paths = tree(&composed);
+    assert!(paths.contains(&String::from("DVD/Caf\u{e9}/Caf\u{e9}.iso")));
+    assert_eq!(tree(&decomposed), paths);
+
+    fs::remove_dir_all(composed).unwrap();
+    fs::remove_dir_all(decomposed).unwrap();
+}
+
+#[test]
+fn links_are_removed_with_the_volume() {
+    let root = crate::utils::test_dir("links-removed");
+    fs::create_dir(root.join("DVD")).unwrap();
+    fs::write(root.join("DVD").join("Cafe\u{301}.iso"), [1; 512]).unwrap();
+    let config = VexFatConfig::new(&root);
+
+    let mut vexfat = VexFat::new(&config).unwrap();
+    let first = vexfat.scratch.0.clone();
+    assert!(first.join("nfc").exists());
+
+    // a rescan maps through links of its own, those of the old volume are gone
+    vexfat.rescan(&config).unwrap();
+    let second = vexfat.scratch.0.clone();
+    assert!(!first.exists());
+    assert!(second.join("nfc").exists());
+
+    drop(vexfat);
+    assert!(!second.exists());
+
+    fs::remove_dir_all(root).unwrap();
+}
+
+#[test]
+fn cluster_sizes() {
+    assert_eq!(parse_cluster_size("512"), Ok(9));
+    assert_eq!(parse_cluster_size("128K"), Ok(17));
+    assert_eq!(parse_cluster_size("1M"), Ok(20));
+    assert_eq!(parse_cluster_size("32M"), Ok(MAX_CLUSTER_SHIFT));
+    assert!(parse_cluster_size("256").is_err());
+    assert!(parse_cluster_size("64M").is_err());
+    assert!(parse_cluster_size("96K").is_err());
+}
+
+#[test]
+fn sector_sizes() {
+    assert_eq!(parse_sector_size("512"), Ok(9));
+    assert_eq!(parse_sector_size("4K"), Ok(12));
+    assert!(parse_sector_size("8K").is_err());
+    assert!(parse_sector_size("1000").is_err());
+
+    let root = crate::utils::test_dir("sector-sizes");
+    fs::write(root.join("game.iso"), [1; 10000]).unwrap();
+
+    let mut vexfat = test_vexfat(&root, |config| {
+        config.bytes_per_sector_shift = 12;
+        config.sectors_per_cluster_shift = 2;
+    });
+    assert_eq!(vexfat.sector_size(), 4096);
+    let tree = crate::exfat::read_tree(&mut vexfat).unwrap();
+    let (_, game) = tree.iter().find(|(path, _)| path == "game.iso").unwrap();
+    assert_eq!(game.size, 10000);
+
+    fs::remove_dir_all(root).unwrap();
 }